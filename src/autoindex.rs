@@ -0,0 +1,159 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use chrono::prelude::*;
+
+use percent_encoding::{
+    AsciiSet,
+    CONTROLS,
+    utf8_percent_encode,
+};
+
+use crate::http::utils::{escape_html, fill_placeholders};
+
+/// Characters that have to be escaped in a path segment of a link
+pub const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>')
+    .add(b'?').add(b'`').add(b'{').add(b'}').add(b'\'').add(b'/');
+
+/// The page the listing is rendered into
+const TEMPLATE: &str = include_str!("autoindex.html");
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+}
+
+/// Renders an HTML listing of a directory
+///
+/// `uri` is the (percent-decoded) path the directory was requested at, which has to end with a
+/// slash for the relative links to work. Entries `hidden` returns true for are left out.
+pub fn render<F: Fn(&Path) -> bool>(dir: &Path, uri: &str, hidden: F) -> io::Result<String> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if hidden(&entry.path()) {
+            continue;
+        }
+
+        // Follow symlinks so they're listed as whatever they point to
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+
+        entries.push(Entry {
+            name: entry.file_name().to_string_lossy().to_string(),
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        });
+    }
+
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let title = format!("Index of {}", escape_html(uri));
+    let mut rows = String::new();
+
+    if uri != "/" {
+        rows.push_str("            <tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+
+    for entry in &entries {
+        let (href, label) = if entry.is_dir {
+            (format!("{}/", utf8_percent_encode(&entry.name, SEGMENT)), format!("{}/", escape_html(&entry.name)))
+        } else {
+            (utf8_percent_encode(&entry.name, SEGMENT).to_string(), escape_html(&entry.name))
+        };
+
+        let (size_sort, size) = if entry.is_dir {
+            (0, "-".to_string())
+        } else {
+            (entry.size, human_size(entry.size))
+        };
+
+        let (modified_sort, modified) = match entry.modified {
+            Some(m) => (m.timestamp(), m.format("%Y-%m-%d %H:%M").to_string()),
+            None => (0, "-".to_string()),
+        };
+
+        rows.push_str(&format!(
+            "            <tr class=\"entry\" data-dir=\"{}\"><td data-sort=\"{}\"><a href=\"{}\">{}</a></td>\
+             <td class=\"size\" data-sort=\"{}\">{}</td><td data-sort=\"{}\">{}</td></tr>\n",
+            entry.is_dir as u8, escape_html(&entry.name), escape_html(&href), label, size_sort, size, modified_sort, modified
+        ));
+    }
+
+    // Filled in one pass, since the title comes from the URI and could have `{{rows}}` in it
+    Ok(fill_placeholders(TEMPLATE, |name| match name {
+        "title" => Some(title.clone()),
+        "rows" => Some(rows.trim_end().to_string()),
+        _ => None,
+    }))
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lists_directories() {
+        let dir = std::env::temp_dir().join(format!("selfserve-autoindex-{}", std::process::id()));
+        fs::create_dir_all(dir.join("zeta")).unwrap();
+        fs::write(dir.join("alpha.txt"), "hi").unwrap();
+        fs::write(dir.join("<b>&.html"), "").unwrap();
+        fs::write(dir.join(".env"), "SECRET=1").unwrap();
+
+        let hidden = |path: &Path| path.file_name().unwrap() == ".env";
+        let page = render(&dir, "/files/", hidden).unwrap();
+
+        assert!(page.contains("<title>Index of /files/</title>"));
+        assert!(page.contains("<a href=\"../\">../</a>"));
+        assert!(page.contains("<a href=\"zeta/\">zeta/</a>"));
+        assert!(page.contains("<a href=\"alpha.txt\">alpha.txt</a>"));
+        assert!(page.contains("<a href=\"%3Cb%3E&amp;.html\">&lt;b&gt;&amp;.html</a>"));
+        assert!(!page.contains("<b>"));
+        assert!(!page.contains(".env"));
+
+        // Directories come first, then files by name
+        let zeta = page.find("zeta/").unwrap();
+        let escaped = page.find("&lt;b&gt;").unwrap();
+        let alpha = page.find("alpha.txt").unwrap();
+        assert!(zeta < escaped && escaped < alpha);
+
+        let placeholder = render(&dir, "/{{rows}}/", hidden).unwrap();
+        assert!(placeholder.contains("<title>Index of /{{rows}}/</title>"));
+        assert_eq!(placeholder.matches("alpha.txt</a>").count(), 1);
+
+        let root = render(&dir, "/", hidden).unwrap();
+        assert!(!root.contains("../"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+}
@@ -0,0 +1,226 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use globset::GlobSet;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::event::ModifyKind;
+
+use crate::http::utils::{escape_html, fill_placeholders};
+use crate::http::ServerBuild;
+use crate::livereload::LiveReload;
+use crate::routing::build_glob_set;
+
+/// How long the source has to be quiet before building, in milliseconds
+pub const DEFAULT_BUILD_DEBOUNCE: u64 = 200;
+
+/// How long a request waits for a build to finish before getting a 503, in seconds
+pub const DEFAULT_BUILD_HOLD: u64 = 10;
+
+/// What's ignored in the source directory by default
+pub const DEFAULT_BUILD_IGNORE: &[&str] = &[".*", "node_modules"];
+
+/// The page shown instead of the site while the build is broken
+const ERROR_TEMPLATE: &str = include_str!("build_error.html");
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildState {
+    /// The last build passed, or hasn't been needed yet
+    Ready,
+    Building,
+    /// The last build failed, with what went wrong and the command's output
+    Failed(String, String),
+}
+
+/// Runs the build command whenever the source changes
+pub struct Builder {
+    settings: ServerBuild,
+    state: Mutex<BuildState>,
+    finished: Condvar,
+    live_reload: Option<Arc<LiveReload>>,
+}
+
+impl Builder {
+    /// Builds the site, then starts watching the source for changes to build again after
+    pub fn start(settings: ServerBuild, live_reload: Option<Arc<LiveReload>>) -> notify::Result<Arc<Self>> {
+        let source = PathBuf::from(&settings.source);
+        let source = source.canonicalize().unwrap_or(source);
+        let ignore = build_glob_set(&settings.ignore.clone().unwrap_or_else(|| {
+            DEFAULT_BUILD_IGNORE.iter().map(|p| p.to_string()).collect()
+        }));
+        let debounce = Duration::from_millis(settings.debounce.unwrap_or(DEFAULT_BUILD_DEBOUNCE));
+
+        let builder = Arc::new(Self {
+            settings,
+            state: Mutex::new(BuildState::Building),
+            finished: Condvar::new(),
+            live_reload,
+        });
+
+        let (tx, rx) = channel();
+        let mut watcher = RecommendedWatcher::new(tx, notify::Config::default())?;
+        watcher.watch(&source, RecursiveMode::Recursive)?;
+
+        let this = builder.clone();
+        thread::spawn(move || {
+            let _watcher = watcher;
+            this.run();
+
+            loop {
+                // Wait for something to change...
+                match rx.recv() {
+                    Ok(Ok(event)) if is_source_change(&event, &source, &ignore) => {},
+                    Ok(_) => continue,
+                    Err(_) => return,
+                }
+
+                // ...then for things to settle down, since saving tends to touch several files
+                loop {
+                    match rx.recv_timeout(debounce) {
+                        Ok(_) => continue,
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+
+                this.run();
+            }
+        });
+
+        Ok(builder)
+    }
+
+    /// Runs the build command and records how it went
+    fn run(&self) {
+        self.set_state(BuildState::Building);
+        println!("Building: {}", self.settings.command);
+        let started = Instant::now();
+
+        let output = shell(&self.settings.command).output();
+        let state = match output {
+            Ok(output) if output.status.success() => {
+                println!("Built in {:.1}s", started.elapsed().as_secs_f32());
+                BuildState::Ready
+            },
+            Ok(output) => {
+                let mut log = String::from_utf8_lossy(&output.stdout).to_string();
+                log.push_str(&String::from_utf8_lossy(&output.stderr));
+                println!("Build failed ({}):\n{}", output.status, log);
+                BuildState::Failed(format!("failed with {}", output.status), log)
+            },
+            Err(e) => {
+                println!("Error running build: {}", e);
+                BuildState::Failed("couldn't be run".to_string(), e.to_string())
+            },
+        };
+
+        self.set_state(state);
+
+        // Pages showing the error (or stale assets) have to find out it's been dealt with
+        if let Some(live_reload) = &self.live_reload {
+            live_reload.reload_all();
+        }
+    }
+
+    fn set_state(&self, state: BuildState) {
+        *self.state.lock().unwrap() = state;
+        self.finished.notify_all();
+    }
+
+    /// Waits for a build in progress to finish, up to the configured hold time, and returns the
+    /// state things are in after
+    pub fn wait(&self) -> BuildState {
+        let hold = Duration::from_secs(self.settings.hold.unwrap_or(DEFAULT_BUILD_HOLD));
+        let state = self.state.lock().unwrap();
+        let (state, _) = self.finished
+            .wait_timeout_while(state, hold, |state| *state == BuildState::Building)
+            .unwrap();
+
+        state.clone()
+    }
+
+    /// Renders the page shown in place of the site while the build is broken
+    pub fn error_page(&self, status: &str, output: &str) -> String {
+        render_error_page(&self.settings.command, status, output)
+    }
+}
+
+/// Fills in the page for a failed build in one pass, so output with placeholders in it is left as
+/// it is
+fn render_error_page(command: &str, status: &str, output: &str) -> String {
+    fill_placeholders(ERROR_TEMPLATE, |name| match name {
+        "command" => Some(escape_html(command)),
+        "status" => Some(escape_html(status)),
+        "output" => Some(escape_html(output)),
+        _ => None,
+    })
+}
+
+/// Whether a watch event is a change to a source file that isn't ignored
+fn is_source_change(event: &Event, source: &Path, ignore: &GlobSet) -> bool {
+    let changes_content = matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_))
+            | EventKind::Modify(ModifyKind::Data(_)) | EventKind::Modify(ModifyKind::Any)
+    );
+
+    changes_content && event.paths.iter().any(|path| {
+        let relative = path.strip_prefix(source).unwrap_or(path);
+        let mut prefix = PathBuf::new();
+        !relative.components().any(|component| {
+            prefix.push(component);
+            ignore.is_match(&prefix)
+        })
+    })
+}
+
+/// Makes a command that runs `command` with the platform's shell
+fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+
+    cmd.arg(command);
+    cmd
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use notify::event::{CreateKind, DataChange};
+
+    #[test]
+    fn ignores_changes_outside_the_source() {
+        let ignore = build_glob_set(&[".*".to_string(), "node_modules".to_string(), "/dist".to_string()]);
+        let source = Path::new("/site");
+        let change = |kind, path: &str| is_source_change(&Event::new(kind).add_path(path.into()), source, &ignore);
+        let edit = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+
+        assert!(change(edit, "/site/src/main.scss"));
+        assert!(change(EventKind::Create(CreateKind::File), "/site/index.md"));
+        assert!(!change(edit, "/site/.git/index"));
+        assert!(!change(edit, "/site/node_modules/sass/sass.js"));
+        assert!(!change(edit, "/site/dist/style.css"));
+        assert!(!change(EventKind::Access(notify::event::AccessKind::Any), "/site/index.md"));
+    }
+
+    #[test]
+    fn renders_error_pages_in_one_pass() {
+        let page = render_error_page("echo {{output}}", "exit status: 1", "<b>{{status}}</b>");
+        assert!(page.contains("echo {{output}}"));
+        assert!(page.contains("&lt;b&gt;{{status}}&lt;/b&gt;"));
+        assert_eq!(page.matches("exit status: 1").count(), ERROR_TEMPLATE.matches("{{status}}").count());
+    }
+}
@@ -0,0 +1,473 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use super::types::HttpRequest;
+use super::parser::{ParseError, READ_CHUNK_LEN, parse_header_line};
+use super::headers::HeaderMap;
+
+/// The longest chunk size or trailer line a chunked body may contain
+const MAX_CHUNK_LINE_LEN: usize = 4096;
+
+/// The longest the trailer section after a chunked body may be, line endings included
+pub const MAX_TRAILERS_LEN: usize = 8 * 1024;
+
+/// The most fields the trailer section after a chunked body may have
+pub const MAX_TRAILER_FIELDS: usize = 32;
+
+/*** REQUEST BODIES ***/
+
+/// How the length of a request body is determined (RFC 7230 §3.3.3)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyFraming {
+    /// The request has no body
+    Empty,
+    /// The body is exactly this many bytes long
+    Length(u64),
+    /// The body is sent with `Transfer-Encoding: chunked`
+    Chunked,
+}
+
+impl BodyFraming {
+    pub fn from_request(request: &HttpRequest) -> Result<Self, ParseError> {
+        let content_length = request.header("Content-Length");
+        let transfer_encoding = request.header("Transfer-Encoding");
+
+        match (content_length, transfer_encoding) {
+            (Some(_), Some(_)) => Err(ParseError::AmbiguousLength),
+
+            (None, Some(te)) => {
+                let codings: Vec<String> = te.split(',')
+                    .map(|c| c.trim().to_ascii_lowercase())
+                    .filter(|c| !c.is_empty())
+                    .collect();
+
+                match codings.split_last() {
+                    Some((last, rest)) if last == "chunked" => {
+                        // Nothing else is decoded, and chunked can only be applied once
+                        match rest.first() {
+                            Some(c) if c == "chunked" => Err(ParseError::InvalidTransferEncoding(te.to_string())),
+                            Some(c) => Err(ParseError::UnsupportedTransferEncoding(c.to_string())),
+                            None => Ok(BodyFraming::Chunked),
+                        }
+                    },
+                    _ => Err(ParseError::InvalidTransferEncoding(te.to_string())),
+                }
+            },
+
+            (Some(cl), None) => {
+                // A repeated Content-Length is fine as long as every value agrees
+                let mut lengths = cl.split(',').map(|l| {
+                    let l = l.trim();
+                    if l.is_empty() || !l.bytes().all(|b| b.is_ascii_digit()) {
+                        return Err(ParseError::InvalidContentLength);
+                    }
+                    l.parse::<u64>().map_err(|_| ParseError::InvalidContentLength)
+                });
+
+                let first = lengths.next().unwrap()?;
+                for length in lengths {
+                    if length? != first {
+                        return Err(ParseError::InvalidContentLength);
+                    }
+                }
+
+                Ok(if first == 0 { BodyFraming::Empty } else { BodyFraming::Length(first) })
+            },
+
+            (None, None) => Ok(BodyFraming::Empty),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BodyState {
+    /// This many bytes of a `Content-Length` body are left
+    Length(u64),
+    /// Waiting on a chunk size line
+    ChunkSize,
+    /// This many bytes of the current chunk are left
+    ChunkData(u64),
+    /// Waiting on the CRLF after a chunk
+    ChunkEnd,
+    /// Reading the trailer section after the last chunk
+    Trailers,
+    Done,
+}
+
+/// A streaming reader over a request body
+///
+/// Reads return the decoded body and hit EOF once the body is over, leaving the stream positioned
+/// at the start of the next request.
+pub struct RequestBody<'a, R> {
+    buffer: &'a mut Vec<u8>,
+    stream: &'a mut R,
+    state: BodyState,
+    trailers: HeaderMap,
+    /// How many bytes of trailers have been read, to hold them to `MAX_TRAILERS_LEN`
+    trailers_len: usize,
+}
+
+impl<'a, R: Read> RequestBody<'a, R> {
+    pub(super) fn new(buffer: &'a mut Vec<u8>, stream: &'a mut R, framing: BodyFraming) -> Self {
+        let state = match framing {
+            BodyFraming::Empty => BodyState::Done,
+            BodyFraming::Length(n) => BodyState::Length(n),
+            BodyFraming::Chunked => BodyState::ChunkSize,
+        };
+
+        Self {
+            buffer,
+            stream,
+            state,
+            trailers: HeaderMap::new(),
+            trailers_len: 0,
+        }
+    }
+
+    /// The trailer fields sent after a chunked body
+    ///
+    /// This is only filled in once the body has been read to the end.
+    #[cfg(test)]
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
+    }
+
+    /// Reads and throws away whatever is left of the body, returning how many bytes that was
+    pub fn discard(&mut self) -> io::Result<u64> {
+        io::copy(self, &mut io::sink())
+    }
+
+    /// Reads more bytes from the stream into the buffer
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0; READ_CHUNK_LEN];
+        let n = self.stream.read(&mut chunk)?;
+        if n == 0 {
+            return Err(ParseError::UnexpectedEof.into());
+        }
+        self.buffer.extend_from_slice(&chunk[..n]);
+
+        Ok(())
+    }
+
+    /// Reads up to `remaining` bytes of body data into `out`
+    fn read_data(&mut self, out: &mut [u8], remaining: u64) -> io::Result<usize> {
+        let want = out.len().min(remaining as usize);
+
+        // Skip the buffer altogether when it's empty
+        if self.buffer.is_empty() {
+            let n = self.stream.read(&mut out[..want])?;
+            if n == 0 {
+                return Err(ParseError::UnexpectedEof.into());
+            }
+            return Ok(n);
+        }
+
+        let n = want.min(self.buffer.len());
+        out[..n].copy_from_slice(&self.buffer[..n]);
+        self.buffer.drain(..n);
+
+        Ok(n)
+    }
+
+    /// Reads a CRLF- or LF-terminated line, without the line ending
+    fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = self.buffer.drain(..=pos).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(line);
+            }
+
+            if self.buffer.len() > MAX_CHUNK_LINE_LEN {
+                return Err(ParseError::InvalidChunk.into());
+            }
+
+            self.fill()?;
+        }
+    }
+}
+
+impl<R: Read> Read for RequestBody<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            match self.state {
+                BodyState::Done => return Ok(0),
+
+                BodyState::Length(remaining) => {
+                    let n = self.read_data(out, remaining)?;
+                    let remaining = remaining - n as u64;
+                    self.state = if remaining == 0 { BodyState::Done } else { BodyState::Length(remaining) };
+                    return Ok(n);
+                },
+
+                BodyState::ChunkSize => {
+                    let line = self.read_line()?;
+                    self.state = match parse_chunk_size(&line)? {
+                        0 => BodyState::Trailers,
+                        size => BodyState::ChunkData(size),
+                    };
+                },
+
+                BodyState::ChunkData(remaining) => {
+                    let n = self.read_data(out, remaining)?;
+                    let remaining = remaining - n as u64;
+                    self.state = if remaining == 0 { BodyState::ChunkEnd } else { BodyState::ChunkData(remaining) };
+                    return Ok(n);
+                },
+
+                BodyState::ChunkEnd => {
+                    if !self.read_line()?.is_empty() {
+                        return Err(ParseError::InvalidChunk.into());
+                    }
+                    self.state = BodyState::ChunkSize;
+                },
+
+                BodyState::Trailers => {
+                    let line = self.read_line()?;
+                    if line.is_empty() {
+                        self.state = BodyState::Done;
+                    } else {
+                        self.trailers_len += line.len() + 2;
+                        if self.trailers_len > MAX_TRAILERS_LEN || self.trailers.iter().count() >= MAX_TRAILER_FIELDS {
+                            return Err(ParseError::TrailersTooLarge.into());
+                        }
+
+                        let (name, value) = parse_header_line(&line)?;
+                        self.trailers.append(name, value);
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Parses a chunk size line, ignoring any chunk extensions
+fn parse_chunk_size(line: &[u8]) -> Result<u64, ParseError> {
+    let size = match line.iter().position(|&b| b == b';') {
+        Some(semicolon) => &line[..semicolon],
+        None => line,
+    };
+    let size = std::str::from_utf8(size)
+        .map_err(|_| ParseError::InvalidChunk)?
+        .trim_end_matches(&[' ', '\t'][..]);
+
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ParseError::InvalidChunk);
+    }
+
+    u64::from_str_radix(size, 16).map_err(|_| ParseError::InvalidChunk)
+}
+
+/*** RESPONSE BODIES ***/
+
+/// How much of a response body is written to the peer at a time
+pub const WRITE_CHUNK_LEN: usize = 64 * 1024;
+
+/// Where the body of a response comes from
+///
+/// Bodies are streamed to the peer in chunks of at most `WRITE_CHUNK_LEN` bytes, so serving a huge
+/// file doesn't mean holding all of it in memory.
+pub enum ResponseBody {
+    Empty,
+    Bytes(Vec<u8>),
+    /// An open file and how many bytes of it to send
+    File(File, u64),
+    /// Anything else that can be read from, with its length if that's known up front. Bodies of
+    /// unknown length are sent with chunked transfer encoding.
+    Reader(Box<dyn Read + Send>, Option<u64>),
+}
+
+impl ResponseBody {
+    /// Opens a file to be sent as a body
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(io::Error::other("not a regular file"));
+        }
+
+        Ok(ResponseBody::File(file, metadata.len()))
+    }
+
+    /// The length of the body in bytes, if it's known before sending it
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            ResponseBody::Empty => Some(0),
+            ResponseBody::Bytes(b) => Some(b.len() as u64),
+            ResponseBody::File(_, len) => Some(*len),
+            ResponseBody::Reader(_, len) => *len,
+        }
+    }
+
+    /// Turns the body into a plain reader, e.g. to feed it through an encoder
+    pub fn into_reader(self) -> Box<dyn Read + Send> {
+        match self {
+            ResponseBody::Empty => Box::new(io::empty()),
+            ResponseBody::Bytes(b) => Box::new(io::Cursor::new(b)),
+            ResponseBody::File(f, len) => Box::new(f.take(len)),
+            ResponseBody::Reader(r, Some(len)) => Box::new(r.take(len)),
+            ResponseBody::Reader(r, None) => r,
+        }
+    }
+
+    /// Writes the body to `stream`, chunked if `chunked` is set
+    pub fn write_to<W: Write>(self, stream: &mut W, chunked: bool) -> io::Result<()> {
+        let expected = self.len();
+        let written = match self {
+            ResponseBody::Empty => 0,
+            ResponseBody::Bytes(b) if !chunked => {
+                stream.write_all(&b)?;
+                b.len() as u64
+            },
+            ResponseBody::Bytes(b) => copy_body(&mut &b[..], stream, chunked)?,
+            ResponseBody::File(f, len) => copy_body(&mut f.take(len), stream, chunked)?,
+            ResponseBody::Reader(r, Some(len)) => copy_body(&mut r.take(len), stream, chunked)?,
+            ResponseBody::Reader(mut r, None) => copy_body(&mut r, stream, chunked)?,
+        };
+
+        // If a file shrank after its length was sent the peer would wait forever for the rest of
+        // it, so bail and let the connection close instead
+        match expected {
+            Some(len) if len != written => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("body ended after {} of {} bytes", written, len)
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for ResponseBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.len() {
+            Some(len) => write!(f, "ResponseBody({} bytes)", len),
+            None => write!(f, "ResponseBody(streamed)"),
+        }
+    }
+}
+
+/// Copies a body to the peer in bounded pieces, framing each one as a chunk if asked to
+fn copy_body<R: Read + ?Sized, W: Write>(reader: &mut R, stream: &mut W, chunked: bool) -> io::Result<u64> {
+    let mut buffer = vec![0; WRITE_CHUNK_LEN];
+    let mut written = 0;
+
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        if chunked {
+            write!(stream, "{:x}\r\n", n)?;
+            stream.write_all(&buffer[..n])?;
+            stream.write_all(b"\r\n")?;
+        } else {
+            stream.write_all(&buffer[..n])?;
+        }
+        written += n as u64;
+    }
+
+    if chunked {
+        stream.write_all(b"0\r\n\r\n")?;
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use crate::http::{RequestParser, StatusCode};
+
+    #[test]
+    fn reads_content_length_body() {
+        let mut stream = Cursor::new(&b"llo world\r\nGET / HTTP/1.0\r\n\r\n"[..]);
+        let mut parser = RequestParser::new();
+        parser.feed(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 11\r\n\r\nhe");
+        let request = parser.parse().unwrap().unwrap();
+
+        let mut body = String::new();
+        parser.body(&request, &mut stream).unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello world");
+
+        let next = parser.read_from(&mut stream).unwrap().unwrap();
+        assert_eq!(next.version, "HTTP/1.0");
+    }
+
+    #[test]
+    fn reads_chunked_body_with_trailers() {
+        let mut stream = Cursor::new(
+            &b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\n"[..]
+        );
+        let mut parser = RequestParser::new();
+        parser.feed(b"PUT / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n");
+        let request = parser.parse().unwrap().unwrap();
+
+        let mut body = parser.body(&request, &mut stream).unwrap();
+        let mut text = String::new();
+        body.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello world");
+        assert_eq!(body.trailers().get("Expires"), Some("never"));
+
+        // The trailer section is held to a limit like the head is
+        let trailers_status = |trailer: String, count: usize| {
+            let mut stream = Cursor::new(format!("0\r\n{}\r\n", trailer.repeat(count)).into_bytes());
+            let error = RequestParser::new().body(&request, &mut stream).unwrap().discard().unwrap_err();
+            ParseError::from(error).status()
+        };
+        assert_eq!(trailers_status("X-A: b\r\n".to_string(), MAX_TRAILER_FIELDS + 1), StatusCode::RequestHeaderFieldsTooLarge);
+        assert_eq!(trailers_status(format!("X-A: {}\r\n", "b".repeat(1000)), 10), StatusCode::RequestHeaderFieldsTooLarge);
+    }
+
+    #[test]
+    fn rejects_ambiguous_body_framing() {
+        let framing = |headers: &str| {
+            let request: HttpRequest = format!("POST / HTTP/1.1\r\nHost: a\r\n{}\r\n", headers)
+                .parse()
+                .unwrap();
+            BodyFraming::from_request(&request).map_err(|e| e.status().code())
+        };
+
+        assert_eq!(framing(""), Ok(BodyFraming::Empty));
+        assert_eq!(framing("Content-Length: 3\r\nContent-Length: 3\r\n"), Ok(BodyFraming::Length(3)));
+        assert_eq!(framing("Content-Length: 3\r\nContent-Length: 4\r\n"), Err(400));
+        assert_eq!(framing("Content-Length: -1\r\n"), Err(400));
+        assert_eq!(framing("Content-Length: 3\r\nTransfer-Encoding: chunked\r\n"), Err(400));
+        assert_eq!(framing("Transfer-Encoding: chunked, gzip\r\n"), Err(400));
+        assert_eq!(framing("Transfer-Encoding: gzip, chunked\r\n"), Err(501));
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let mut stream = Cursor::new(&b"zz\r\nhello\r\n0\r\n\r\n"[..]);
+        let mut parser = RequestParser::new();
+        parser.feed(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n");
+        let request = parser.parse().unwrap().unwrap();
+
+        let error = parser.body(&request, &mut stream).unwrap().discard().unwrap_err();
+        assert_eq!(ParseError::from(error).status(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn writes_bodies_of_unknown_length_as_chunks() {
+        let body = ResponseBody::Reader(Box::new(Cursor::new(b"hello".to_vec())), None);
+        let mut out = vec![];
+        body.write_to(&mut out, true).unwrap();
+        assert_eq!(out, b"5\r\nhello\r\n0\r\n\r\n");
+
+        // A body that comes up short of its stated length is an error
+        let body = ResponseBody::Reader(Box::new(Cursor::new(b"hello".to_vec())), Some(10));
+        assert!(body.write_to(&mut vec![], false).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+use std::fs::{self, Metadata};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use chrono::prelude::*;
+use super::types::{HttpRequest, HttpResponse, ServerCaching};
+use super::method::Method;
+use super::status::StatusCode;
+use super::body::ResponseBody;
+use super::encoding::ContentCoding;
+use super::utils::*;
+
+/*** VALIDATORS ***/
+
+/// The validators for a file, derived from its metadata (RFC 7232 §2)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Validators {
+    /// A strong entity tag made from the modification time and length, like Apache and nginx do
+    pub etag: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+impl Validators {
+    pub fn from_metadata(metadata: &Metadata) -> Option<Self> {
+        let modified = metadata.modified().ok()?;
+        let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+
+        Some(Self {
+            etag: format!(
+                "\"{:x}.{:x}-{:x}\"",
+                since_epoch.as_secs(),
+                since_epoch.subsec_nanos(),
+                metadata.len()
+            ),
+            last_modified: truncate_to_seconds(modified),
+        })
+    }
+
+    /// Makes the entity tag specific to a content coding, since a compressed body is a different
+    /// representation from the file itself
+    pub fn for_coding(mut self, coding: ContentCoding) -> Self {
+        if coding != ContentCoding::Identity {
+            self.etag = format!("{}-{}\"", self.etag.trim_end_matches('"'), coding.token());
+        }
+
+        self
+    }
+}
+
+/// What the conditional headers on a request say to do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precondition {
+    /// Carry on with the request as normal
+    Proceed,
+    /// The client's cached copy is still good (304)
+    NotModified,
+    /// A precondition the client set doesn't hold (412)
+    Failed,
+}
+
+impl Precondition {
+    /// Evaluates the conditional headers of a request in the order RFC 7232 §6 lays out
+    pub fn evaluate(request: &HttpRequest, validators: &Validators) -> Self {
+        let is_get_or_head = request.method == Method::Get || request.method == Method::Head;
+
+        // 1. If-Match, or else 2. If-Unmodified-Since
+        if let Some(if_match) = request.header("If-Match") {
+            if !etag_list_matches(&if_match, &validators.etag, false) {
+                return Precondition::Failed;
+            }
+        } else if let Some(date) = request.headers.get("If-Unmodified-Since").and_then(parse_http_date) {
+            if validators.last_modified > date {
+                return Precondition::Failed;
+            }
+        }
+
+        // 3. If-None-Match, or else 4. If-Modified-Since
+        if let Some(if_none_match) = request.header("If-None-Match") {
+            if etag_list_matches(&if_none_match, &validators.etag, true) {
+                return if is_get_or_head { Precondition::NotModified } else { Precondition::Failed };
+            }
+        } else if is_get_or_head {
+            if let Some(date) = request.headers.get("If-Modified-Since").and_then(parse_http_date) {
+                if validators.last_modified <= date {
+                    return Precondition::NotModified;
+                }
+            }
+        }
+
+        Precondition::Proceed
+    }
+}
+
+/// Checks whether an `If-Match`/`If-None-Match` header lists `etag`
+///
+/// `If-None-Match` uses the weak comparison function and `If-Match` the strong one
+/// (RFC 7232 §2.3.2).
+fn etag_list_matches(header: &str, etag: &str, weak: bool) -> bool {
+    if header.trim() == "*" {
+        return true;
+    }
+
+    parse_etag_list(header).iter().any(|tag| {
+        match tag.strip_prefix("W/") {
+            Some(opaque) => weak && opaque == etag.trim_start_matches("W/"),
+            None => tag == &etag,
+        }
+    })
+}
+
+/// Splits a list of entity tags, leaving out anything that isn't a quoted tag
+fn parse_etag_list(header: &str) -> Vec<&str> {
+    let mut tags = vec![];
+    let mut rest = header;
+
+    loop {
+        rest = rest.trim_start_matches(&[',', ' ', '\t'][..]);
+        if rest.is_empty() {
+            break;
+        }
+
+        let opaque_start = if rest.starts_with("W/") { 2 } else { 0 };
+        if !rest[opaque_start..].starts_with('"') {
+            // Skip whatever this is up to the next comma
+            rest = rest.find(',').map_or("", |i| &rest[i..]);
+            continue;
+        }
+
+        match rest[opaque_start + 1..].find('"') {
+            Some(close) => {
+                let end = opaque_start + close + 2;
+                tags.push(&rest[..end]);
+                rest = &rest[end..];
+            },
+            None => break,
+        }
+    }
+
+    tags
+}
+
+/*** CONDITIONAL RESPONSES ***/
+
+impl<'r> HttpResponse<'r> {
+    /// Adds `ETag` and `Last-Modified` for a file sent with `coding` and applies the request's
+    /// preconditions to them, turning a 200 into a 304 or 412 as needed
+    pub fn with_validators(self, request: &HttpRequest, path: &Path, coding: ContentCoding) -> Self {
+        let validators = match fs::metadata(path).ok().as_ref().and_then(Validators::from_metadata) {
+            Some(v) => v.for_coding(coding),
+            None => return self,
+        };
+
+        let response = self
+            .with_header("ETag", &validators.etag)
+            .with_header("Last-Modified", format_http_date(validators.last_modified));
+
+        if response.status != StatusCode::Ok {
+            return response;
+        }
+
+        match Precondition::evaluate(request, &validators) {
+            Precondition::Proceed => response,
+            Precondition::NotModified => response.not_modified(),
+            Precondition::Failed => response.with_status(StatusCode::PreconditionFailed).with_body(ResponseBody::Empty),
+        }
+    }
+
+    /// Adds `Cache-Control` to a response for a file if the settings ask for it
+    pub fn with_cache_control(self, caching: &ServerCaching) -> Self {
+        let max_age = match caching.max_age {
+            Some(max_age) => max_age,
+            None => return self,
+        };
+
+        let mut cache_control = format!("public, max-age={}", max_age);
+        if caching.immutable.unwrap_or(false) {
+            cache_control.push_str(", immutable");
+        }
+
+        self.with_header("Cache-Control", cache_control)
+    }
+
+    /// Turns the response into a 304, which has no body and only keeps the headers a 200 would
+    /// have needed to update the cache (RFC 7232 §4.1)
+    pub fn not_modified(mut self) -> Self {
+        for header in &["Content-Type", "Content-Length", "Transfer-Encoding", "Content-Range", "Accept-Ranges"] {
+            self.headers.remove(header);
+        }
+        self.body = ResponseBody::Empty;
+
+        self.with_status(StatusCode::NotModified)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evaluates_preconditions_in_order() {
+        let validators = Validators {
+            etag: "\"abc\"".to_string(),
+            last_modified: parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap(),
+        };
+        let evaluate = |method: &str, headers: &str| {
+            let request: HttpRequest = format!("{} / HTTP/1.1\r\nHost: a\r\n{}\r\n", method, headers)
+                .parse()
+                .unwrap();
+            Precondition::evaluate(&request, &validators)
+        };
+
+        assert_eq!(evaluate("GET", ""), Precondition::Proceed);
+        assert_eq!(evaluate("GET", "If-None-Match: \"xyz\", W/\"abc\"\r\n"), Precondition::NotModified);
+        assert_eq!(evaluate("PUT", "If-None-Match: *\r\n"), Precondition::Failed);
+        assert_eq!(evaluate("GET", "If-Modified-Since: Wed, 21 Oct 2015 07:28:00 GMT\r\n"), Precondition::NotModified);
+        assert_eq!(evaluate("GET", "If-Modified-Since: Tue, 20 Oct 2015 07:28:00 GMT\r\n"), Precondition::Proceed);
+
+        // If-None-Match takes precedence over If-Modified-Since
+        assert_eq!(
+            evaluate("GET", "If-None-Match: \"xyz\"\r\nIf-Modified-Since: Wed, 21 Oct 2015 07:28:00 GMT\r\n"),
+            Precondition::Proceed
+        );
+
+        // If-Match uses the strong comparison, and is checked before anything else
+        assert_eq!(evaluate("GET", "If-Match: W/\"abc\"\r\n"), Precondition::Failed);
+        assert_eq!(evaluate("GET", "If-Match: \"abc\"\r\nIf-None-Match: \"abc\"\r\n"), Precondition::NotModified);
+        assert_eq!(evaluate("GET", "If-Unmodified-Since: Tue, 20 Oct 2015 07:28:00 GMT\r\n"), Precondition::Failed);
+    }
+}
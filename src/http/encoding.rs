@@ -0,0 +1,275 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use flate2::Compression;
+use flate2::read::{GzEncoder, ZlibEncoder};
+use super::types::{HttpRequest, HttpResponse, ServerCompression};
+use super::status::StatusCode;
+use super::body::ResponseBody;
+
+/// Bodies smaller than this aren't worth compressing by default
+pub const DEFAULT_COMPRESSION_MIN_SIZE: u64 = 1024;
+
+/// zlib's own default, which is a good trade-off between speed and size
+pub const DEFAULT_GZIP_LEVEL: u32 = 6;
+
+/// Brotli gets very slow past 5 or so, which matters when compressing on the fly
+pub const DEFAULT_BROTLI_LEVEL: u32 = 5;
+
+/// The window size brotli compresses with (as a power of two)
+const BROTLI_WINDOW: u32 = 22;
+
+/// The buffer size brotli reads through
+const BROTLI_BUFFER_LEN: usize = 4096;
+
+/*** CONTENT CODINGS ***/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentCoding {
+    Identity,
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl ContentCoding {
+    /// The codings the server can compress with on the fly, in order of preference
+    pub const COMPRESSED: [ContentCoding; 3] = [ContentCoding::Brotli, ContentCoding::Gzip, ContentCoding::Deflate];
+
+    /// The codings a file can have precompressed siblings in, in order of preference
+    pub const PRECOMPRESSED: [ContentCoding; 2] = [ContentCoding::Brotli, ContentCoding::Gzip];
+
+    /// The name of the coding in `Accept-Encoding` and `Content-Encoding`
+    pub fn token(self) -> &'static str {
+        match self {
+            ContentCoding::Identity => "identity",
+            ContentCoding::Brotli => "br",
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Deflate => "deflate",
+        }
+    }
+
+    /// The extension a file precompressed with this coding has tacked onto its name
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            ContentCoding::Brotli => Some("br"),
+            ContentCoding::Gzip => Some("gz"),
+            _ => None,
+        }
+    }
+
+    /// Picks the coding the client likes best out of `offered`, which is in the server's order of
+    /// preference (RFC 7231 §5.3.4)
+    ///
+    /// Falls back to `Identity` if the client doesn't accept any of them.
+    pub fn negotiate(accept_encoding: Option<&str>, offered: &[ContentCoding]) -> Self {
+        let accept_encoding = match accept_encoding {
+            Some(a) => a,
+            // No Accept-Encoding means anything goes, but there's no telling what the client
+            // can actually decode
+            None => return ContentCoding::Identity,
+        };
+
+        let preferences: Vec<(String, f32)> = accept_encoding.split(',')
+            .filter_map(|item| {
+                let mut params = item.split(';');
+                let coding = params.next()?.trim().to_ascii_lowercase();
+                if coding.is_empty() {
+                    return None;
+                }
+
+                let q = params
+                    .filter_map(|p| {
+                        let p = p.trim();
+                        let eq = p.find('=')?;
+                        if p[..eq].trim().eq_ignore_ascii_case("q") {
+                            p[eq + 1..].trim().parse::<f32>().ok()
+                        } else {
+                            None
+                        }
+                    })
+                    .next()
+                    .unwrap_or(1.0);
+
+                Some((coding, q))
+            })
+            .collect();
+
+        let quality = |coding: ContentCoding| {
+            preferences.iter()
+                .find(|(c, _)| c == coding.token() || (coding == ContentCoding::Gzip && c == "x-gzip"))
+                .or_else(|| preferences.iter().find(|(c, _)| c == "*"))
+                .map_or(0.0, |(_, q)| *q)
+        };
+
+        // Ties go to whichever coding comes first in the server's list
+        let mut best = ContentCoding::Identity;
+        let mut best_q = 0.0;
+        for &coding in offered {
+            let q = quality(coding);
+            if q > best_q {
+                best = coding;
+                best_q = q;
+            }
+        }
+
+        best
+    }
+
+    /// Wraps a body in an encoder that compresses it as it's read
+    pub fn encode(self, body: ResponseBody, settings: &ServerCompression) -> ResponseBody {
+        let reader = body.into_reader();
+        let gzip_level = Compression::new(settings.gzip_level.unwrap_or(DEFAULT_GZIP_LEVEL).min(9));
+
+        let encoded: Box<dyn Read + Send> = match self {
+            ContentCoding::Identity => return ResponseBody::Reader(reader, None),
+            ContentCoding::Gzip => Box::new(GzEncoder::new(reader, gzip_level)),
+            // HTTP's "deflate" is really the zlib format (RFC 7230 §4.2.2)
+            ContentCoding::Deflate => Box::new(ZlibEncoder::new(reader, gzip_level)),
+            ContentCoding::Brotli => Box::new(brotli::CompressorReader::new(
+                reader,
+                BROTLI_BUFFER_LEN,
+                settings.brotli_level.unwrap_or(DEFAULT_BROTLI_LEVEL).min(11),
+                BROTLI_WINDOW
+            )),
+        };
+
+        ResponseBody::Reader(encoded, None)
+    }
+}
+
+/*** PRECOMPRESSED FILES ***/
+
+/// The sibling of `path` that holds it precompressed with `coding`, e.g. `app.js.br`
+pub fn sidecar_path(path: &Path, coding: ContentCoding) -> Option<PathBuf> {
+    let extension = coding.extension()?;
+    let mut name = path.file_name()?.to_os_string();
+    name.push(".");
+    name.push(extension);
+
+    Some(path.with_file_name(name))
+}
+
+/// Finds the precompressed sibling of `path` the client likes best, if there are any
+///
+/// Returns every coding that has a sibling on disk along with the pick, since the response varies
+/// on `Accept-Encoding` as soon as there's anything to choose from. Siblings `allowed` returns
+/// false for are treated as if they weren't there, since they're served in place of `path`.
+pub fn find_precompressed<F: Fn(&Path) -> bool>(request: &HttpRequest, path: &Path, settings: &ServerCompression, allowed: F)
+    -> (Vec<ContentCoding>, Option<(ContentCoding, PathBuf)>)
+{
+    if !settings.precompressed.unwrap_or(true) {
+        return (vec![], None);
+    }
+
+    let available: Vec<ContentCoding> = ContentCoding::PRECOMPRESSED.iter()
+        .cloned()
+        .filter(|&coding| {
+            sidecar_path(path, coding)
+                .filter(|p| allowed(p))
+                .and_then(|p| fs::metadata(p).ok())
+                .is_some_and(|m| m.is_file())
+        })
+        .collect();
+
+    // Ranges are served out of the uncompressed file
+    if available.is_empty() || request.header("Range").is_some() {
+        return (available, None);
+    }
+
+    let pick = match ContentCoding::negotiate(request.header("Accept-Encoding").as_deref(), &available) {
+        ContentCoding::Identity => None,
+        coding => sidecar_path(path, coding).map(|p| (coding, p)),
+    };
+
+    (available, pick)
+}
+
+/*** COMPRESSED RESPONSES ***/
+
+impl<'r> HttpResponse<'r> {
+    /// Whether the body of this response is worth compressing at all
+    fn is_compressible(&self) -> bool {
+        self.headers.content_type().is_some_and(|ct| ct.media_type.is_compressible())
+    }
+
+    /// Picks the content coding to send this response's body with
+    pub fn negotiate_coding(&self, request: &HttpRequest, settings: &ServerCompression) -> ContentCoding {
+        let worth_it = settings.enabled.unwrap_or(true)
+            && self.status == StatusCode::Ok
+            && !self.headers.contains("Content-Encoding")
+            // Ranges are served out of the uncompressed file
+            && request.header("Range").is_none()
+            && self.is_compressible()
+            && self.body.len().is_none_or(|len| len >= settings.min_size.unwrap_or(DEFAULT_COMPRESSION_MIN_SIZE));
+
+        if worth_it {
+            ContentCoding::negotiate(request.header("Accept-Encoding").as_deref(), &ContentCoding::COMPRESSED)
+        } else {
+            ContentCoding::Identity
+        }
+    }
+
+    /// Adds `Vary: Accept-Encoding` if the body could be sent compressed, so caches don't hand a
+    /// compressed body to a client that can't decode it
+    pub fn with_encoding_vary(self, settings: &ServerCompression) -> Self {
+        if settings.enabled.unwrap_or(true) && self.is_compressible() {
+            self.with_vary("Accept-Encoding")
+        } else {
+            self
+        }
+    }
+
+    /// Compresses the body with `coding`
+    pub fn with_coding(mut self, coding: ContentCoding, settings: &ServerCompression) -> Self {
+        if coding == ContentCoding::Identity || self.status != StatusCode::Ok {
+            return self;
+        }
+
+        let body = std::mem::replace(&mut self.body, ResponseBody::Empty);
+        self.with_header("Content-Encoding", coding.token())
+            .with_body(coding.encode(body, settings))
+    }
+
+    /// Negotiates a content coding and compresses the body with it
+    pub fn with_compression(self, request: &HttpRequest, settings: &ServerCompression) -> Self {
+        let response = self.with_encoding_vary(settings);
+        let coding = response.negotiate_coding(request, settings);
+        response.with_coding(coding, settings)
+    }
+
+    /// Adds a header name to `Vary`
+    pub fn with_vary(mut self, header: &str) -> Self {
+        self.headers.add_token("Vary", header);
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negotiates_content_coding() {
+        use ContentCoding::*;
+        let negotiate = |accept| ContentCoding::negotiate(accept, &ContentCoding::COMPRESSED);
+
+        assert_eq!(negotiate(None), Identity);
+        assert_eq!(negotiate(Some("gzip, deflate, br")), Brotli);
+        assert_eq!(negotiate(Some("gzip, br;q=0.5")), Gzip);
+        assert_eq!(negotiate(Some("x-gzip")), Gzip);
+        assert_eq!(negotiate(Some("*;q=0.1, br;q=0")), Gzip);
+        assert_eq!(negotiate(Some("identity")), Identity);
+        assert_eq!(negotiate(Some("br;q=0, gzip;q=0, deflate;q=0")), Identity);
+    }
+
+    #[test]
+    fn names_precompressed_sidecars() {
+        let path = Path::new("static/app.min.js");
+
+        assert_eq!(sidecar_path(path, ContentCoding::Brotli), Some(PathBuf::from("static/app.min.js.br")));
+        assert_eq!(sidecar_path(path, ContentCoding::Gzip), Some(PathBuf::from("static/app.min.js.gz")));
+        assert_eq!(sidecar_path(path, ContentCoding::Deflate), None);
+    }
+}
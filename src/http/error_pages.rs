@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::fs;
+
+use super::utils::{escape_html, fill_placeholders};
+use super::types::{HttpResponse, ServerOwner};
+use super::status::StatusCode;
+
+/// The pages for statuses that have one of their own, which are built into the binary so it can
+/// run from anywhere
+const BUILT_IN: &[(StatusCode, &str)] = &[
+    (StatusCode::Forbidden, include_str!("../error_pages/403.html")),
+    (StatusCode::NotFound, include_str!("../error_pages/404.html")),
+    (StatusCode::MethodNotAllowed, include_str!("../error_pages/405.html")),
+    (StatusCode::MisdirectedRequest, include_str!("../error_pages/421.html")),
+    (StatusCode::ServiceUnavailable, include_str!("../error_pages/503.html")),
+];
+
+/// The page for every other status
+const GENERIC: &str = include_str!("../error_pages/error.html");
+
+/// The built-in page for a status, before it's filled in
+pub fn built_in(status: StatusCode) -> &'static str {
+    BUILT_IN.iter()
+        .find(|(s, _)| *s == status)
+        .map_or(GENERIC, |(_, page)| page)
+}
+
+/// The pages sent for error statuses, from the files in the config or the built-in ones
+///
+/// Pages are templates, with `{{status}}`, `{{reason}}`, `{{uri}}`, `{{owner_name}}`,
+/// `{{owner_email}}` and `{{owner_website}}` filled in, and `{{contact}}` replaced with a line
+/// saying who to contact if there's an owner.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    files: HashMap<u32, String>,
+    owner: Option<ServerOwner>,
+}
+
+impl ErrorPages {
+    pub fn new(files: HashMap<u32, String>, owner: Option<ServerOwner>) -> Self {
+        Self {
+            files,
+            owner,
+        }
+    }
+
+    /// Renders the page for a status, for a request for `uri`
+    ///
+    /// The files are read every time, so they can be worked on without restarting the server.
+    pub fn render(&self, status: StatusCode, uri: &str) -> String {
+        let configured = self.files.get(&status.code()).and_then(|file| match fs::read_to_string(file) {
+            Ok(page) => Some(page),
+            Err(e) => {
+                println!("Error reading error page {}: {}", file, e);
+                None
+            }
+        });
+        let page = configured.as_deref().unwrap_or_else(|| built_in(status));
+
+        let owner = self.owner.as_ref();
+        let contact = owner.map_or(String::new(), |owner| format!(
+            "<p>If you think this is a mistake, contact <a href=\"mailto:{}\">{}</a></p>",
+            escape_html(&owner.email),
+            escape_html(&owner.name)
+        ));
+
+        // Filled in one pass, so placeholders in the values (like a URI with `{{contact}}` in it)
+        // are left as they are
+        fill_placeholders(page, |name| Some(match name {
+            "status" => status.code().to_string(),
+            "reason" => status.reason().to_string(),
+            "uri" => escape_html(uri),
+            "owner_name" => escape_html(owner.map_or("", |o| &o.name)),
+            "owner_email" => escape_html(owner.map_or("", |o| &o.email)),
+            "owner_website" => escape_html(owner.and_then(|o| o.website.as_deref()).unwrap_or("")),
+            "contact" => contact.clone(),
+            _ => return None,
+        }))
+    }
+
+    /// Gives an error response the page for its status
+    ///
+    /// Only responses with a stand-in page are changed, so files served with an error status
+    /// (like a `_redirects` rule's 404 page) are left alone.
+    pub fn apply<'r>(&self, response: HttpResponse<'r>) -> HttpResponse<'r> {
+        if !response.error_page {
+            return response;
+        }
+
+        let page = self.render(response.status, response.uri);
+        response.with_error_page(page)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_error_pages() {
+        let owner = ServerOwner {
+            name: "Jo <Admin>".to_string(),
+            email: "jo@example.com".to_string(),
+            website: None,
+        };
+        let pages = ErrorPages::new(Default::default(), Some(owner));
+
+        let page = pages.render(StatusCode::NotFound, "/<script>");
+        assert!(page.contains("The server cannot find /&lt;script&gt;."));
+        assert!(page.contains("<a href=\"mailto:jo@example.com\">Jo &lt;Admin&gt;</a>"));
+
+        let page = pages.render(StatusCode::NotFound, "/{{contact}}{{owner_email}}{{uri}}");
+        assert!(page.contains("The server cannot find /{{contact}}{{owner_email}}{{uri}}."));
+        assert_eq!(page.matches("mailto:").count(), 1);
+
+        let page = pages.render(StatusCode::InternalServerError, "/");
+        assert!(page.contains("<title>500 Internal Server Error</title>"));
+        assert!(!page.contains("{{"));
+
+        let anonymous = ErrorPages::default().render(StatusCode::MethodNotAllowed, "/");
+        assert!(anonymous.contains("405 Method Not Allowed") && !anonymous.contains("{{contact}}"));
+    }
+}
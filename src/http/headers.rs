@@ -0,0 +1,213 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::iter::FromIterator;
+
+use super::types::ContentType;
+
+/// Header fields, kept in the order they were added
+///
+/// Names are matched ignoring case, but sent as they were given. A field can be added more than
+/// once, which is how `Set-Cookie` has to be sent (RFC 7230 §3.2.2).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderMap {
+    fields: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The first value of a header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    /// Every value of a header, in order
+    pub fn get_all<'h: 'n, 'n>(&'h self, name: &'n str) -> impl Iterator<Item = &'h str> + 'n {
+        self.fields.iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of a header as one comma-separated list, which means the same thing for any
+    /// header that's allowed to be repeated (RFC 7230 §3.2.2)
+    pub fn get_combined(&self, name: &str) -> Option<Cow<'_, str>> {
+        let mut values = self.get_all(name);
+        let first = values.next()?;
+
+        Some(values.fold(Cow::Borrowed(first), |mut combined, value| {
+            let combined_mut = combined.to_mut();
+            combined_mut.push_str(", ");
+            combined_mut.push_str(value);
+            combined
+        }))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Whether a comma-separated header has a token in it, ignoring case
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Sets a header, replacing any values it already had but keeping its place
+    pub fn insert<N: Into<String>, V: Into<String>>(&mut self, name: N, value: V) {
+        let (name, value) = (name.into(), value.into());
+        if !is_safe_value(&name, &value) {
+            return;
+        }
+
+        match self.fields.iter().position(|(n, _)| n.eq_ignore_ascii_case(&name)) {
+            Some(i) => {
+                self.fields[i].1 = value;
+
+                // Drop the values after the first
+                let mut index = 0;
+                self.fields.retain(|(n, _)| {
+                    let keep = index <= i || !n.eq_ignore_ascii_case(&name);
+                    index += 1;
+                    keep
+                });
+            },
+            None => self.fields.push((name, value)),
+        }
+    }
+
+    /// Adds another value for a header, after any it already has
+    pub fn append<N: Into<String>, V: Into<String>>(&mut self, name: N, value: V) {
+        let (name, value) = (name.into(), value.into());
+        if is_safe_value(&name, &value) {
+            self.fields.push((name, value));
+        }
+    }
+
+    /// Adds a token to a comma-separated header, unless it's already there
+    pub fn add_token(&mut self, name: &str, token: &str) {
+        if self.has_token(name, token) {
+            return;
+        }
+
+        match self.fields.iter_mut().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some((_, value)) => {
+                value.push_str(", ");
+                value.push_str(token);
+            },
+            None => self.append(name, token),
+        }
+    }
+
+    /// Removes every value of a header, returning whether it had any
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.fields.len();
+        self.fields.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.fields.len() != len
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /*** TYPED HEADERS ***/
+
+    pub fn content_type(&self) -> Option<ContentType<'_>> {
+        self.get("Content-Type").and_then(ContentType::parse)
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.get("Host")
+    }
+
+    pub fn etag(&self) -> Option<&str> {
+        self.get("ETag")
+    }
+}
+
+/// Whether a header's values can be joined into one comma-separated list, which every header's can
+/// but `Set-Cookie`'s (RFC 7230 §3.2.2)
+pub fn is_list(name: &str) -> bool {
+    !name.eq_ignore_ascii_case("Set-Cookie")
+}
+
+/// Whether a value can be sent as it is, which it can't if it has a line break that would end the
+/// field early and start another one
+fn is_safe_value(name: &str, value: &str) -> bool {
+    let safe = !value.contains(['\r', '\n', '\0']);
+    if !safe {
+        println!("Refusing to send a {} header with a line break in it: {:?}", name, value);
+    }
+
+    safe
+}
+
+/// Writes the fields as they're sent, each followed by a CRLF
+impl fmt::Display for HeaderMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in self.iter() {
+            write!(f, "{}: {}\r\n", name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<N: Into<String>, V: Into<String>> FromIterator<(N, V)> for HeaderMap {
+    fn from_iter<I: IntoIterator<Item = (N, V)>>(iter: I) -> Self {
+        let mut headers = Self::new();
+        for (name, value) in iter {
+            headers.append(name, value);
+        }
+
+        headers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::{HttpRequest, HttpResponse, MediaType, StatusCode};
+
+    #[test]
+    fn keeps_headers_in_order() {
+        let mut headers: HeaderMap = vec![("Content-Type", "text/html"), ("Set-Cookie", "a=1")].into_iter().collect();
+        headers.append("set-cookie", "b=2");
+        headers.insert("Cache-Control", "no-cache");
+        headers.insert("content-type", "text/plain");
+        headers.add_token("Vary", "Accept-Encoding");
+        headers.add_token("vary", "accept-encoding");
+        headers.add_token("Vary", "Origin");
+
+        assert_eq!(headers.get("SET-COOKIE"), Some("a=1"));
+        assert_eq!(headers.get_all("Set-Cookie").collect::<Vec<_>>(), vec!["a=1", "b=2"]);
+        assert_eq!(headers.get_combined("set-cookie").as_deref(), Some("a=1, b=2"));
+        assert_eq!(headers.content_type().map(|ct| ct.media_type), Some(MediaType::Text("plain")));
+        assert_eq!(
+            headers.to_string(),
+            "Content-Type: text/plain\r\nSet-Cookie: a=1\r\nset-cookie: b=2\r\nCache-Control: no-cache\r\nVary: Accept-Encoding, Origin\r\n",
+        );
+
+        headers.insert("Set-Cookie", "c=3");
+        assert!(headers.remove("Cache-Control") && !headers.remove("Cache-Control"));
+        assert_eq!(headers.iter().map(|(name, _)| name).collect::<Vec<_>>(), vec!["Content-Type", "Set-Cookie", "Vary"]);
+    }
+
+    #[test]
+    fn refuses_line_breaks_in_headers() {
+        let injected = "GET /blog/x%0d%0aSet-Cookie:%20pwned=1 HTTP/1.1\r\nHost: a\r\n\r\n".parse::<HttpRequest>();
+        assert_eq!(injected.unwrap_err().status(), StatusCode::BadRequest);
+
+        let mut request: HttpRequest = "GET /?a=1 HTTP/1.1\r\nHost: a\r\n\r\n".parse().unwrap();
+        assert!(request.set_target("/%00".to_string()).is_err());
+        assert_eq!(request.uri, "/?a=1");
+
+        let response = HttpResponse::redirect(&request, StatusCode::Found, "/x\r\nSet-Cookie: pwned=1")
+            .with_header("X-Ok", "fine");
+        let mut headers = response.headers.clone();
+        headers.append("Set-Cookie", "a=1\nb=2");
+        assert_eq!(headers.to_string(), "Content-Length: 0\r\nX-Ok: fine\r\n");
+    }
+}
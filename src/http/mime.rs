@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use super::types::{ContentType, MediaType};
+
+/// How much of a file is looked at to work out what it is, same as the WHATWG sniffing algorithm
+pub const SNIFF_LEN: usize = 512;
+
+/// File extensions and the media types they map to, after Apache's and nginx's `mime.types`
+const MIME_TYPES: &[(&str, &str)] = &[
+    // Text
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("shtml", "text/html"),
+    ("xhtml", "application/xhtml+xml"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("cjs", "text/javascript"),
+    ("txt", "text/plain"),
+    ("text", "text/plain"),
+    ("conf", "text/plain"),
+    ("log", "text/plain"),
+    ("ini", "text/plain"),
+    ("md", "text/markdown"),
+    ("markdown", "text/markdown"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("ics", "text/calendar"),
+    ("vcf", "text/vcard"),
+    ("vtt", "text/vtt"),
+    ("srt", "application/x-subrip"),
+    ("xml", "text/xml"),
+    ("xsl", "application/xslt+xml"),
+    ("xslt", "application/xslt+xml"),
+    ("rtf", "application/rtf"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("toml", "application/toml"),
+    ("sh", "application/x-sh"),
+    ("csh", "application/x-csh"),
+    ("c", "text/x-c"),
+    ("h", "text/x-c"),
+    ("cpp", "text/x-c"),
+    ("java", "text/x-java-source"),
+    ("py", "text/x-python"),
+    ("rs", "text/x-rust"),
+    ("appcache", "text/cache-manifest"),
+
+    // Structured data
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("webmanifest", "application/manifest+json"),
+    ("geojson", "application/geo+json"),
+    ("atom", "application/atom+xml"),
+    ("rss", "application/rss+xml"),
+    ("rdf", "application/rdf+xml"),
+    ("wasm", "application/wasm"),
+
+    // Images
+    ("png", "image/png"),
+    ("apng", "image/apng"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("jpe", "image/jpeg"),
+    ("jfif", "image/jpeg"),
+    ("pjpeg", "image/jpeg"),
+    ("pjp", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("heic", "image/heic"),
+    ("heif", "image/heif"),
+    ("jxl", "image/jxl"),
+    ("svg", "image/svg+xml"),
+    ("svgz", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("cur", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("psd", "image/vnd.adobe.photoshop"),
+
+    // Audio
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("aac", "audio/aac"),
+    ("oga", "audio/ogg"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("weba", "audio/webm"),
+    ("flac", "audio/flac"),
+    ("mid", "audio/midi"),
+    ("midi", "audio/midi"),
+
+    // Video
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("mpeg", "video/mpeg"),
+    ("mpg", "video/mpeg"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mov", "video/quicktime"),
+    ("avi", "video/x-msvideo"),
+    ("wmv", "video/x-ms-wmv"),
+    ("mkv", "video/x-matroska"),
+    ("3gp", "video/3gpp"),
+    ("3g2", "video/3gpp2"),
+    ("ts", "video/mp2t"),
+    ("m3u8", "application/vnd.apple.mpegurl"),
+    ("mpd", "application/dash+xml"),
+
+    // Fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("ttc", "font/collection"),
+    ("eot", "application/vnd.ms-fontobject"),
+
+    // 3D models
+    ("gltf", "model/gltf+json"),
+    ("glb", "model/gltf-binary"),
+    ("obj", "model/obj"),
+    ("stl", "model/stl"),
+
+    // Documents
+    ("pdf", "application/pdf"),
+    ("ps", "application/postscript"),
+    ("eps", "application/postscript"),
+    ("epub", "application/epub+zip"),
+    ("doc", "application/msword"),
+    ("docx", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+    ("xls", "application/vnd.ms-excel"),
+    ("xlsx", "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    ("pptx", "application/vnd.openxmlformats-officedocument.presentationml.presentation"),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("odp", "application/vnd.oasis.opendocument.presentation"),
+
+    // Archives and binaries
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("bz2", "application/x-bzip2"),
+    ("xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("br", "application/x-brotli"),
+    ("tar", "application/x-tar"),
+    ("7z", "application/x-7z-compressed"),
+    ("rar", "application/vnd.rar"),
+    ("jar", "application/java-archive"),
+    ("apk", "application/vnd.android.package-archive"),
+    ("deb", "application/vnd.debian.binary-package"),
+    ("rpm", "application/x-rpm"),
+    ("dmg", "application/x-apple-diskimage"),
+    ("iso", "application/x-iso9660-image"),
+    ("exe", "application/vnd.microsoft.portable-executable"),
+    ("msi", "application/x-msdownload"),
+    ("bin", "application/octet-stream"),
+    ("pem", "application/x-pem-file"),
+    ("crt", "application/x-x509-ca-cert"),
+    ("der", "application/x-x509-ca-cert"),
+];
+
+lazy_static! {
+    static ref REGISTRY: HashMap<&'static str, &'static str> = MIME_TYPES.iter().cloned().collect();
+}
+
+/// Looks up the media type for a file extension, ignoring case
+pub fn lookup_extension(extension: &str) -> Option<MediaType<'static>> {
+    let extension = extension.to_ascii_lowercase();
+    REGISTRY.get(extension.as_str()).and_then(|mt| MediaType::parse(mt))
+}
+
+/// Looks up a file extension in the `mime_types` overrides from the config, which may or may not
+/// have a leading dot
+pub fn lookup_override<'c>(overrides: &'c HashMap<String, String>, extension: &str) -> Option<ContentType<'c>> {
+    overrides.iter()
+        .find(|(ext, _)| ext.trim_start_matches('.').eq_ignore_ascii_case(extension))
+        .and_then(|(ext, content_type)| {
+            let parsed = ContentType::parse(content_type);
+            if parsed.is_none() {
+                println!("Ignoring invalid MIME type {:?} for .{}", content_type, ext.trim_start_matches('.'));
+            }
+            parsed
+        })
+}
+
+/*** CONTENT SNIFFING ***/
+
+/// Reads the start of a file and works out what it is from that
+pub fn sniff_file(path: &Path) -> io::Result<MediaType<'static>> {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?.take(SNIFF_LEN as u64).read_to_end(&mut head)?;
+
+    Ok(sniff(&head))
+}
+
+/// Works out what a file is from its first few bytes, loosely following the WHATWG MIME Sniffing
+/// standard
+///
+/// Anything that isn't recognized is `text/plain` if it looks like text and
+/// `application/octet-stream` otherwise.
+pub fn sniff(head: &[u8]) -> MediaType<'static> {
+    use self::MediaType::*;
+
+    const SIGNATURES: &[(&[u8], MediaType<'static>)] = &[
+        (b"\x89PNG\r\n\x1a\n", Image("png")),
+        (b"\xff\xd8\xff", Image("jpeg")),
+        (b"GIF87a", Image("gif")),
+        (b"GIF89a", Image("gif")),
+        (b"BM", Image("bmp")),
+        (b"\x00\x00\x01\x00", Image("x-icon")),
+        (b"%PDF-", Application("pdf")),
+        (b"%!PS-Adobe-", Application("postscript")),
+        (b"\x00asm", Application("wasm")),
+        (b"PK\x03\x04", Application("zip")),
+        (b"\x1f\x8b\x08", Application("gzip")),
+        (b"BZh", Application("x-bzip2")),
+        (b"\xfd7zXZ\x00", Application("x-xz")),
+        (b"7z\xbc\xaf\x27\x1c", Application("x-7z-compressed")),
+        (b"Rar!\x1a\x07", Application("vnd.rar")),
+        (b"\x7fELF", Application("octet-stream")),
+        (b"ID3", Audio("mpeg")),
+        (b"OggS\x00", Audio("ogg")),
+        (b"fLaC", Audio("flac")),
+        (b"MThd\x00\x00\x00\x06", Audio("midi")),
+        (b"\x1a\x45\xdf\xa3", Video("webm")),
+        (b"wOFF", Font("woff")),
+        (b"wOF2", Font("woff2")),
+        (b"OTTO", Font("otf")),
+        (b"\x00\x01\x00\x00\x00", Font("ttf")),
+    ];
+
+    for (signature, media_type) in SIGNATURES {
+        if head.starts_with(signature) {
+            return *media_type;
+        }
+    }
+
+    // RIFF containers say what they hold at offset 8
+    if head.len() >= 12 && head.starts_with(b"RIFF") {
+        match &head[8..12] {
+            b"WEBP" => return Image("webp"),
+            b"WAVE" => return Audio("wav"),
+            b"AVI " => return Video("x-msvideo"),
+            _ => {},
+        }
+    }
+
+    // ISO base media files have an `ftyp` box up front with the brand in it
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return match &head[8..12] {
+            b"avif" | b"avis" => Image("avif"),
+            b"heic" | b"heix" | b"mif1" => Image("heic"),
+            b"qt  " => Video("quicktime"),
+            b"M4A " => Audio("mp4"),
+            _ => Video("mp4"),
+        };
+    }
+
+    let text = match std::str::from_utf8(trim_partial_char(head)) {
+        Ok(text) => text.trim_start_matches('\u{feff}').trim_start(),
+        Err(_) => return Application("octet-stream"),
+    };
+
+    // Binary files are full of control characters that text files never have
+    if text.bytes().any(|b| b < 0x20 && !b"\t\n\x0c\r\x1b".contains(&b)) {
+        return Application("octet-stream");
+    }
+
+    let lower = text.get(..text.len().min(64)).unwrap_or(text).to_ascii_lowercase();
+    if lower.starts_with("<!doctype html") || lower.starts_with("<html") || lower.starts_with("<head")
+        || lower.starts_with("<body") || lower.starts_with("<!--")
+    {
+        Text("html")
+    } else if lower.starts_with("<svg") {
+        Image("svg+xml")
+    } else if lower.starts_with("<?xml") {
+        if text.contains("<svg") { Image("svg+xml") } else { Text("xml") }
+    } else if lower.starts_with("%pdf-") {
+        Application("pdf")
+    } else {
+        Text("plain")
+    }
+}
+
+/// Cuts off a UTF-8 sequence the sniffing window split in two, so it isn't mistaken for binary
+fn trim_partial_char(head: &[u8]) -> &[u8] {
+    match std::str::from_utf8(head) {
+        Err(e) if e.error_len().is_none() => &head[..e.valid_up_to()],
+        _ => head,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn looks_up_media_types() {
+        let content_type = |ext| lookup_extension(ext).map(|mt| ContentType::from_media_type(mt).to_string());
+
+        assert_eq!(content_type("wasm").as_deref(), Some("application/wasm"));
+        assert_eq!(content_type("MP4").as_deref(), Some("video/mp4"));
+        assert_eq!(content_type("txt").as_deref(), Some("text/plain; charset=utf-8"));
+        assert_eq!(content_type("nope"), None);
+
+        let mut overrides = HashMap::new();
+        overrides.insert(".wasm".to_string(), "application/x-custom; foo=bar".to_string());
+        let overridden = lookup_override(&overrides, "WASM").unwrap();
+        assert_eq!(overridden.to_string(), "application/x-custom; foo=bar");
+    }
+
+    #[test]
+    fn sniffs_extensionless_files() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), MediaType::Image("png"));
+        assert_eq!(sniff(b"\0asm\x01\0\0\0"), MediaType::Application("wasm"));
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42"), MediaType::Video("mp4"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), MediaType::Image("webp"));
+        assert_eq!(sniff(b"  <!DOCTYPE html><html>"), MediaType::Text("html"));
+        assert_eq!(sniff("plain old caf\u{e9}".as_bytes()), MediaType::Text("plain"));
+        assert_eq!(sniff(b"\x01\x02\x03\xff"), MediaType::Application("octet-stream"));
+    }
+}
@@ -1,6 +1,19 @@
 #[macro_use] pub mod utils;
 mod types;
+mod method;
+mod status;
+pub mod headers;
+mod query;
+mod parser;
+pub mod body;
+pub mod multipart;
+pub mod range;
+pub mod conditional;
+pub mod encoding;
+pub mod mime;
+pub mod error_pages;
 pub use self::types::*;
-
-#[cfg(test)]
-mod test {}
\ No newline at end of file
+pub use self::method::*;
+pub use self::status::*;
+pub use self::query::*;
+pub use self::parser::*;
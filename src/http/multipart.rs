@@ -0,0 +1,299 @@
+use std::io::{self, Read};
+
+use percent_encoding::percent_decode_str;
+
+use super::headers::HeaderMap;
+use super::parser::{ParseError, READ_CHUNK_LEN, parse_header_line};
+
+/// The longest the headers of a single part may be
+pub const MAX_PART_HEAD_LEN: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Reading the preamble or a part, up to the next delimiter
+    Body,
+    /// Just past a delimiter, with the headers of the next part (or the end) to come
+    Delimiter,
+    Done,
+}
+
+/// A streaming reader over a `multipart/form-data` body (RFC 7578)
+///
+/// Parts are read one at a time with `next_part`, and only a few kilobytes of the body are held
+/// in memory at once however large the parts are.
+pub struct Multipart<R> {
+    reader: R,
+    /// `CRLF--boundary`, which comes before every part and after the last one
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    state: State,
+}
+
+impl<R: Read> Multipart<R> {
+    pub fn new(reader: R, boundary: &str) -> Self {
+        Self {
+            reader,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // The first delimiter doesn't have a line ending before it unless there's a preamble,
+            // so pretend there was one
+            buffer: b"\r\n".to_vec(),
+            state: State::Body,
+        }
+    }
+
+    /// Moves on to the next part, skipping whatever's left of the current one
+    pub fn next_part(&mut self) -> Result<Option<Part<'_, R>>, ParseError> {
+        while self.state == State::Body {
+            let mut skipped = [0; READ_CHUNK_LEN];
+            self.read_body(&mut skipped)?;
+        }
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        // The delimiter is followed by `--` after the last part, and a line break before the
+        // next part's headers otherwise
+        while self.buffer.len() < 2 {
+            self.fill()?;
+        }
+        if self.buffer.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+
+        // Anything up to the line break is padding and ignored
+        self.read_line()?;
+
+        let mut headers = HeaderMap::new();
+        let mut head_len = 0;
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+
+            head_len += line.len();
+            if head_len > MAX_PART_HEAD_LEN {
+                return Err(ParseError::InvalidMultipart(format!("part headers longer than {} bytes", MAX_PART_HEAD_LEN)));
+            }
+            let (name, value) = parse_header_line(&line)?;
+            headers.append(name, value);
+        }
+
+        self.state = State::Body;
+        Ok(Some(Part {
+            headers,
+            multipart: self,
+        }))
+    }
+
+    /// Reads more of the body into the buffer, failing if it's over
+    fn fill(&mut self) -> Result<(), ParseError> {
+        let mut chunk = [0; READ_CHUNK_LEN];
+        let n = self.reader.read(&mut chunk)?;
+        if n == 0 {
+            return Err(ParseError::InvalidMultipart("body ended before the closing boundary".to_string()));
+        }
+        self.buffer.extend_from_slice(&chunk[..n]);
+
+        Ok(())
+    }
+
+    /// Reads a CRLF-terminated line, without the line ending
+    fn read_line(&mut self) -> Result<Vec<u8>, ParseError> {
+        loop {
+            if let Some(pos) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                let mut line: Vec<u8> = self.buffer.drain(..pos + 2).collect();
+                line.truncate(pos);
+                return Ok(line);
+            }
+
+            if self.buffer.len() > MAX_PART_HEAD_LEN {
+                return Err(ParseError::InvalidMultipart(format!("part headers longer than {} bytes", MAX_PART_HEAD_LEN)));
+            }
+
+            self.fill()?;
+        }
+    }
+
+    /// Reads data up to the next delimiter into `out`, returning 0 once it's reached
+    fn read_body(&mut self, out: &mut [u8]) -> Result<usize, ParseError> {
+        if self.state != State::Body || out.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let delimiter_at = self.buffer.windows(self.delimiter.len()).position(|w| w == &self.delimiter[..]);
+            // Everything before the delimiter is data, and so is everything it can't start in
+            let data_len = match delimiter_at {
+                Some(0) => {
+                    self.buffer.drain(..self.delimiter.len());
+                    self.state = State::Delimiter;
+                    return Ok(0);
+                },
+                Some(i) => i,
+                None => self.buffer.len().saturating_sub(self.delimiter.len() - 1),
+            };
+
+            if data_len > 0 {
+                let n = data_len.min(out.len());
+                out[..n].copy_from_slice(&self.buffer[..n]);
+                self.buffer.drain(..n);
+                return Ok(n);
+            }
+
+            self.fill()?;
+        }
+    }
+}
+
+/// The boundary parameter of a `multipart/form-data` content type, if that's what it is
+pub fn boundary(content_type: &str) -> Option<String> {
+    let params = split_params(content_type);
+    if !params[0].trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    params[1..].iter()
+        .find_map(|param| param_value(param, "boundary"))
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+/// One part of a multipart body, which reads as the part's content
+pub struct Part<'m, R> {
+    pub headers: HeaderMap,
+    multipart: &'m mut Multipart<R>,
+}
+
+impl<R> Part<'_, R> {
+    /// The name of the form field the part is for
+    pub fn name(&self) -> Option<String> {
+        self.disposition_param("name")
+    }
+
+    /// The name of the file the part is, if it's a file
+    ///
+    /// The `filename*` form (RFC 5987) is preferred, since it's the one that can say what the
+    /// name is encoded in.
+    pub fn filename(&self) -> Option<String> {
+        let extended = self.disposition_param("filename*").and_then(|value| {
+            let (charset, rest) = value.split_once('\'')?;
+            let (_, encoded) = rest.split_once('\'')?;
+            match charset.to_ascii_lowercase().as_str() {
+                "utf-8" => Some(percent_decode_str(encoded).decode_utf8_lossy().to_string()),
+                _ => None,
+            }
+        });
+
+        extended.or_else(|| self.disposition_param("filename"))
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type")
+    }
+
+    fn disposition_param(&self, name: &str) -> Option<String> {
+        let disposition = self.headers.get("Content-Disposition")?;
+        split_params(disposition).iter().skip(1).find_map(|param| param_value(param, name))
+    }
+}
+
+impl<R: Read> Read for Part<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        Ok(self.multipart.read_body(out)?)
+    }
+}
+
+/// Splits a header value on the semicolons that aren't in quoted strings
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = vec![];
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(&value[start..i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    params.push(&value[start..]);
+
+    params
+}
+
+/// The value of a `name=value` parameter if it has the name, with the quotes taken off a quoted
+/// string
+fn param_value(param: &str, name: &str) -> Option<String> {
+    let (key, value) = param.split_once('=')?;
+    if !key.trim().eq_ignore_ascii_case(name) {
+        return None;
+    }
+
+    let value = value.trim();
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(quoted) => {
+            let mut unquoted = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                unquoted.push(if c == '\\' { chars.next().unwrap_or(c) } else { c });
+            }
+            Some(unquoted)
+        },
+        None => Some(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn streams_multipart_parts() {
+        // Hands the body over a few bytes at a time, so delimiters get split across reads
+        struct Trickle<'b>(&'b [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(self.0.len()).min(5);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        assert_eq!(boundary("text/plain; boundary=xyz"), None);
+        let boundary = boundary("multipart/form-data; boundary=\"xyz;1\"").unwrap();
+        assert_eq!(boundary, "xyz;1");
+
+        let body = b"preamble\r\n--xyz;1\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n\
+                     --xyz;1\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a;b.txt\"\r\n\
+                     Content-Type: text/plain\r\n\r\nline one\r\n--xyz;not yet\r\n--xyz;1\r\n\
+                     Content-Disposition: form-data; name=\"skipped\"\r\n\r\nunread\r\n--xyz;1--\r\nepilogue";
+        let mut form = Multipart::new(Trickle(body), &boundary);
+
+        let mut part = form.next_part().unwrap().unwrap();
+        assert_eq!((part.name().as_deref(), part.filename()), (Some("title"), None));
+        let mut value = String::new();
+        part.read_to_string(&mut value).unwrap();
+        assert_eq!(value, "Hello");
+
+        let mut part = form.next_part().unwrap().unwrap();
+        assert_eq!(part.filename().as_deref(), Some("a;b.txt"));
+        assert_eq!(part.content_type(), Some("text/plain"));
+        let mut content = String::new();
+        part.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "line one\r\n--xyz;not yet");
+
+        assert_eq!(form.next_part().unwrap().unwrap().name().as_deref(), Some("skipped"));
+        assert!(form.next_part().unwrap().is_none());
+
+        let mut cut_off = Multipart::new(Cursor::new(&b"--xyz\r\n\r\nno end"[..]), "xyz");
+        let mut part = cut_off.next_part().unwrap().unwrap();
+        assert!(part.read_to_end(&mut vec![]).is_err());
+    }
+}
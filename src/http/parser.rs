@@ -0,0 +1,413 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use chrono::prelude::*;
+use super::types::HttpRequest;
+use super::method::Method;
+use super::status::StatusCode;
+use super::headers::HeaderMap;
+use super::query::split_target;
+use super::body::{RequestBody, BodyFraming, MAX_TRAILERS_LEN, MAX_TRAILER_FIELDS};
+
+/// The largest request line the parser will accept before giving up with a 414
+pub const MAX_REQUEST_LINE_LEN: usize = 8 * 1024;
+
+/// The largest request head (request line plus headers) the parser will buffer before giving up
+/// with a 431
+pub const MAX_HEAD_LEN: usize = 64 * 1024;
+
+/// How much to read from the peer at a time
+pub(super) const READ_CHUNK_LEN: usize = 4096;
+
+/*** PARSE ERRORS ***/
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// The peer closed the connection partway through a request
+    UnexpectedEof,
+    /// The request line isn't `method SP request-target SP HTTP-version`
+    InvalidRequestLine,
+    /// The method contains characters that aren't allowed in a token
+    InvalidMethod,
+    /// The request target is empty or contains whitespace or control characters
+    InvalidUri,
+    /// The version isn't of the form `HTTP/x.y`
+    InvalidVersion,
+    /// The version is well-formed but isn't HTTP/1.x
+    UnsupportedVersion(String),
+    /// A header line is malformed
+    InvalidHeader(String),
+    /// An HTTP/1.1 request didn't send exactly one `Host` header
+    InvalidHost,
+    /// The request line is longer than `MAX_REQUEST_LINE_LEN`
+    UriTooLong,
+    /// The request head is longer than `MAX_HEAD_LEN`
+    HeadTooLarge,
+    /// The trailer section of a chunked body is longer than `MAX_TRAILERS_LEN` or has more than
+    /// `MAX_TRAILER_FIELDS` fields
+    TrailersTooLarge,
+    /// `Content-Length` isn't a non-negative integer
+    InvalidContentLength,
+    /// The request sends both `Content-Length` and `Transfer-Encoding`, which is how requests get
+    /// smuggled past proxies
+    AmbiguousLength,
+    /// `Transfer-Encoding` doesn't end with `chunked`
+    InvalidTransferEncoding(String),
+    /// `Transfer-Encoding` uses a coding other than `chunked`
+    UnsupportedTransferEncoding(String),
+    /// A chunk size line or chunk delimiter in a chunked body is malformed
+    InvalidChunk,
+    /// The body is longer than this many bytes, which is all the server will read of it
+    BodyTooLarge(u64),
+    /// A `multipart/form-data` body is malformed
+    InvalidMultipart(String),
+    /// Reading from the peer failed
+    Io(io::Error),
+}
+
+impl ParseError {
+    /// The status code the server should answer with when this error occurs
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ParseError::UnsupportedVersion(_) => StatusCode::HttpVersionNotSupported,
+            ParseError::UriTooLong => StatusCode::UriTooLong,
+            ParseError::HeadTooLarge | ParseError::TrailersTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            ParseError::UnsupportedTransferEncoding(_) => StatusCode::NotImplemented,
+            ParseError::BodyTooLarge(_) => StatusCode::PayloadTooLarge,
+            _ => StatusCode::BadRequest,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedEof => write!(f, "connection closed before the request was complete"),
+            ParseError::InvalidRequestLine => write!(f, "malformed request line"),
+            ParseError::InvalidMethod => write!(f, "malformed request method"),
+            ParseError::InvalidUri => write!(f, "malformed request target"),
+            ParseError::InvalidVersion => write!(f, "malformed HTTP version"),
+            ParseError::UnsupportedVersion(v) => write!(f, "unsupported HTTP version {}", v),
+            ParseError::InvalidHeader(h) => write!(f, "malformed header line {:?}", h),
+            ParseError::InvalidHost => write!(f, "HTTP/1.1 requests must send exactly one Host header"),
+            ParseError::UriTooLong => write!(f, "request line is longer than {} bytes", MAX_REQUEST_LINE_LEN),
+            ParseError::HeadTooLarge => write!(f, "request head is longer than {} bytes", MAX_HEAD_LEN),
+            ParseError::TrailersTooLarge => {
+                write!(f, "trailer section is longer than {} bytes or {} fields", MAX_TRAILERS_LEN, MAX_TRAILER_FIELDS)
+            },
+            ParseError::InvalidContentLength => write!(f, "malformed Content-Length"),
+            ParseError::AmbiguousLength => write!(f, "request sends both Content-Length and Transfer-Encoding"),
+            ParseError::InvalidTransferEncoding(te) => write!(f, "chunked must be the final transfer coding, got {:?}", te),
+            ParseError::UnsupportedTransferEncoding(te) => write!(f, "unsupported transfer coding {:?}", te),
+            ParseError::InvalidChunk => write!(f, "malformed chunked body"),
+            ParseError::BodyTooLarge(max) => write!(f, "request body is longer than {} bytes", max),
+            ParseError::InvalidMultipart(why) => write!(f, "malformed multipart body: {}", why),
+            ParseError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    /// Unwraps parse errors that had to be smuggled through `Read` as I/O errors
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<ParseError>()) {
+            *e.into_inner().unwrap().downcast::<ParseError>().unwrap()
+        } else {
+            ParseError::Io(e)
+        }
+    }
+}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::Io(e) => e,
+            ParseError::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/*** PARSER ***/
+
+/// An incremental parser for HTTP/1.x request heads
+///
+/// Bytes can be fed to it in whatever pieces they arrive in. Once a whole head has been buffered
+/// `parse` hands back the request and leaves anything after the head (the body or a pipelined
+/// request) in the buffer.
+#[derive(Debug, Default)]
+pub struct RequestParser {
+    buffer: Vec<u8>,
+    // How far into the buffer we've already looked for the end of the head
+    scanned: usize,
+}
+
+impl RequestParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends bytes received from the peer to the buffer
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// How many bytes of the next request have been received so far
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Tries to parse a request head out of the buffered bytes
+    ///
+    /// Returns `Ok(None)` if the head isn't complete yet.
+    pub fn parse(&mut self) -> Result<Option<HttpRequest>, ParseError> {
+        // Per RFC 7230 §3.5, empty lines before the request line are ignored
+        let leading = self.buffer.iter()
+            .take_while(|&&b| b == b'\r' || b == b'\n')
+            .count();
+        if leading > 0 {
+            self.buffer.drain(..leading);
+            self.scanned = 0;
+        }
+
+        let end = match find_head_end(&self.buffer, self.scanned) {
+            Some(end) => end,
+            None => {
+                self.scanned = self.buffer.len().saturating_sub(3);
+                let first_line_len = self.buffer.iter()
+                    .position(|&b| b == b'\n')
+                    .unwrap_or(self.buffer.len());
+                if first_line_len > MAX_REQUEST_LINE_LEN {
+                    return Err(ParseError::UriTooLong);
+                }
+                if self.buffer.len() > MAX_HEAD_LEN {
+                    return Err(ParseError::HeadTooLarge);
+                }
+                return Ok(None);
+            }
+        };
+
+        if end > MAX_HEAD_LEN {
+            return Err(ParseError::HeadTooLarge);
+        }
+
+        let head: Vec<u8> = self.buffer.drain(..end).collect();
+        self.scanned = 0;
+
+        parse_head(&head).map(Some)
+    }
+
+    /// Works out how the body of `request` is framed and returns a reader for it
+    ///
+    /// The body is read out of this parser's buffer first and then from `stream`. Anything read
+    /// past the end of the body is left in the buffer for the next request.
+    pub fn body<'a, R: Read>(&'a mut self, request: &HttpRequest, stream: &'a mut R)
+        -> Result<RequestBody<'a, R>, ParseError>
+    {
+        let framing = BodyFraming::from_request(request)?;
+        self.scanned = 0;
+        Ok(RequestBody::new(&mut self.buffer, stream, framing))
+    }
+
+    /// Reads from `reader` until a whole request head has been received
+    ///
+    /// Returns `Ok(None)` if the peer closes the connection cleanly before sending anything.
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> Result<Option<HttpRequest>, ParseError> {
+        let mut chunk = [0; READ_CHUNK_LEN];
+        loop {
+            if let Some(request) = self.parse()? {
+                return Ok(Some(request));
+            }
+
+            let n = reader.read(&mut chunk)?;
+            if n == 0 {
+                return if self.buffer.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(ParseError::UnexpectedEof)
+                };
+            }
+
+            self.feed(&chunk[..n]);
+        }
+    }
+}
+
+/// Finds the index just past the blank line that ends a request head, accepting bare LFs as line
+/// endings
+fn find_head_end(buffer: &[u8], from: usize) -> Option<usize> {
+    let mut i = from;
+    while i < buffer.len() {
+        if buffer[i] == b'\n' {
+            match buffer.get(i + 1) {
+                Some(b'\n') => return Some(i + 2),
+                Some(b'\r') if buffer.get(i + 2) == Some(&b'\n') => return Some(i + 3),
+                _ => {}
+            }
+        }
+        i += 1;
+    }
+
+    None
+}
+
+fn parse_head(head: &[u8]) -> Result<HttpRequest, ParseError> {
+    let mut lines = head.split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+
+    let request_line = lines.next().ok_or(ParseError::InvalidRequestLine)?;
+    if request_line.len() > MAX_REQUEST_LINE_LEN {
+        return Err(ParseError::UriTooLong);
+    }
+    let request_line = std::str::from_utf8(request_line)
+        .map_err(|_| ParseError::InvalidRequestLine)?;
+
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    if parts.len() != 3 {
+        return Err(ParseError::InvalidRequestLine);
+    }
+    let (method, uri, version) = (parts[0], parts[1], parts[2]);
+
+    if method.is_empty() || !method.bytes().all(is_tchar) {
+        return Err(ParseError::InvalidMethod);
+    }
+
+    if uri.is_empty() || !uri.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(ParseError::InvalidUri);
+    }
+
+    let version_number = version.strip_prefix("HTTP/").ok_or(ParseError::InvalidVersion)?;
+    match version_number.as_bytes() {
+        [major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+            if *major != b'1' {
+                return Err(ParseError::UnsupportedVersion(version.to_string()));
+            }
+        },
+        _ => return Err(ParseError::InvalidVersion),
+    }
+
+    let mut headers = HeaderMap::new();
+    let mut host_count = 0;
+    for line in lines {
+        // The head ends with a blank line
+        if line.is_empty() {
+            break;
+        }
+
+        let (name, value) = parse_header_line(line)?;
+        if name.eq_ignore_ascii_case("host") {
+            host_count += 1;
+        }
+        headers.append(name, value);
+    }
+
+    if (version == "HTTP/1.1" && host_count != 1) || host_count > 1 {
+        return Err(ParseError::InvalidHost);
+    }
+
+    let (path, query) = split_target(uri)?;
+    Ok(HttpRequest {
+        method: Method::from(method),
+        uri: uri.to_string(),
+        path,
+        query,
+        version: version.to_string(),
+        headers,
+        timestamp: Utc::now(),
+    })
+}
+
+/// Splits a header line (without its line ending) into a name and a trimmed value
+pub(super) fn parse_header_line(line: &[u8]) -> Result<(String, String), ParseError> {
+    let as_text = || String::from_utf8_lossy(line).to_string();
+
+    // Obsolete line folding isn't worth supporting (RFC 7230 §3.2.4)
+    if line.is_empty() || line[0] == b' ' || line[0] == b'\t' {
+        return Err(ParseError::InvalidHeader(as_text()));
+    }
+
+    let colon = line.iter()
+        .position(|&b| b == b':')
+        .ok_or_else(|| ParseError::InvalidHeader(as_text()))?;
+    let (name, value) = (&line[..colon], &line[colon + 1..]);
+
+    // No whitespace is allowed between the field name and the colon
+    if name.is_empty() || !name.iter().all(|&b| is_tchar(b)) {
+        return Err(ParseError::InvalidHeader(as_text()));
+    }
+
+    if value.iter().any(|&b| b != b'\t' && (b < 0x20 || b == 0x7f)) {
+        return Err(ParseError::InvalidHeader(as_text()));
+    }
+
+    let name = String::from_utf8_lossy(name).to_string();
+    let value = String::from_utf8_lossy(value)
+        .trim_matches(&[' ', '\t'][..])
+        .to_string();
+
+    Ok((name, value))
+}
+
+/// Whether the byte is allowed in a token (RFC 7230 §3.2.6)
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_request_split_across_reads() {
+        let mut parser = RequestParser::new();
+        parser.feed(b"GET /index.html HT");
+        assert!(parser.parse().unwrap().is_none());
+        parser.feed(b"TP/1.1\r\nHost: localhost:8779\r\nAccept: */*\r");
+        assert!(parser.parse().unwrap().is_none());
+        parser.feed(b"\n\r\nGET / HTTP/1.1\r\n");
+
+        let request = parser.parse().unwrap().unwrap();
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.uri, "/index.html");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.header("host").as_deref(), Some("localhost:8779"));
+        assert_eq!(request.header("ACCEPT").as_deref(), Some("*/*"));
+
+        // The start of the next request stays buffered
+        assert!(parser.parse().unwrap().is_none());
+        parser.feed(b"Host: a\r\n\r\n");
+        assert_eq!(parser.parse().unwrap().unwrap().uri, "/");
+    }
+
+    #[test]
+    fn combines_repeated_headers() {
+        let request: HttpRequest = "GET / HTTP/1.0\r\nAccept: text/html\r\naccept: */*\r\n\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(request.header("Accept").as_deref(), Some("text/html, */*"));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let status = |s: &str| s.parse::<HttpRequest>().unwrap_err().status().code();
+
+        assert_eq!(status("GET\r\n\r\n"), 400);
+        assert_eq!(status("GET  / HTTP/1.1\r\nHost: a\r\n\r\n"), 400);
+        assert_eq!(status("G(T / HTTP/1.1\r\nHost: a\r\n\r\n"), 400);
+        assert_eq!(status("GET / HTTX/1.1\r\nHost: a\r\n\r\n"), 400);
+        assert_eq!(status("GET / HTTP/2.0\r\nHost: a\r\n\r\n"), 505);
+        assert_eq!(status("GET / HTTP/1.1\r\nHost : a\r\n\r\n"), 400);
+        assert_eq!(status("GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n"), 400);
+        assert_eq!(status("GET / HTTP/1.1\r\nAccept: */*\r\n\r\n"), 400);
+        assert_eq!(status(&format!("GET /{} HTTP/1.1\r\n", "a".repeat(MAX_REQUEST_LINE_LEN))), 414);
+    }
+}
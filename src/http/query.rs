@@ -0,0 +1,100 @@
+use percent_encoding::percent_decode_str;
+
+use super::parser::ParseError;
+
+/// The largest `application/x-www-form-urlencoded` body that gets parsed
+pub const MAX_FORM_LEN: u64 = 1024 * 1024;
+
+/// Name/value pairs from a query string or a form, in the order they were sent
+///
+/// Names can be repeated, like `?tag=a&tag=b` and checkboxes sharing a name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params {
+    pairs: Vec<(String, String)>,
+}
+
+impl Params {
+    /// Parses `application/x-www-form-urlencoded` text, which is also what query strings are
+    ///
+    /// A pair without an `=` has an empty value, and empty pairs (from `&&`) are skipped.
+    pub fn parse(text: &str) -> Self {
+        Self {
+            pairs: text.split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| match pair.split_once('=') {
+                    Some((name, value)) => (decode_component(name), decode_component(value)),
+                    None => (decode_component(pair), String::new()),
+                })
+                .collect(),
+        }
+    }
+
+    /// The first value of a parameter
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every parameter in the order they were sent, repeats included
+    #[cfg(test)]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+/// Splits a request target into its percent-decoded path and its query, if it has one
+///
+/// The query is left encoded, since decoding it before splitting it into pairs would make an
+/// encoded `&` or `=` look like a separator. Paths that decode to control characters are refused,
+/// since no file is named like that and an encoded line break could end up splitting a header.
+pub fn split_target(target: &str) -> Result<(String, Option<String>), ParseError> {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+
+    let path = percent_decode_str(path).decode_utf8_lossy().to_string();
+    if path.chars().any(char::is_control) {
+        return Err(ParseError::InvalidUri);
+    }
+
+    Ok((path, query))
+}
+
+/// Decodes a name or value from a query string or form, where `+` stands for a space
+fn decode_component(component: &str) -> String {
+    percent_decode_str(&component.replace('+', " ")).decode_utf8_lossy().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use crate::http::{HttpRequest, StatusCode};
+
+    #[test]
+    fn splits_targets_and_parses_forms() {
+        let mut request: HttpRequest = "POST /caf%C3%A9/app.js?v=3&name=J%C3%B6+Doe&flag&&tag=a%26b&tag=c HTTP/1.1\r\n\
+                                        Host: a\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(request.path, "/caf\u{e9}/app.js");
+        assert_eq!(request.query.as_deref(), Some("v=3&name=J%C3%B6+Doe&flag&&tag=a%26b&tag=c"));
+
+        let params = request.query_params();
+        assert_eq!(params.get("name"), Some("J\u{f6} Doe"));
+        assert_eq!(params.get("flag"), Some(""));
+        assert_eq!(params.iter().filter(|(n, _)| *n == "tag").map(|(_, v)| v).collect::<Vec<_>>(), vec!["a&b", "c"]);
+
+        let form = request.form(Cursor::new("comment=100%25+sure&ok=")).unwrap().unwrap();
+        assert_eq!(form.iter().collect::<Vec<_>>(), vec![("comment", "100% sure"), ("ok", "")]);
+        let too_long = request.form(Cursor::new(vec![b'a'; MAX_FORM_LEN as usize + 1])).unwrap_err();
+        assert_eq!(too_long.status(), StatusCode::PayloadTooLarge);
+
+        request.set_target("/index.html".to_string()).unwrap();
+        assert_eq!((request.path.as_str(), request.query.as_deref()), ("/index.html", None));
+        request.headers.insert("Content-Type", "text/plain");
+        assert_eq!(request.form(Cursor::new("a=b")).unwrap(), None);
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::types::{HttpRequest, HttpResponse};
+use super::method::Method;
+use super::status::StatusCode;
+use super::body::ResponseBody;
+use super::utils::*;
+
+/// Requests asking for more (non-overlapping) ranges than this just get the whole file
+pub const MAX_RANGES: usize = 32;
+
+/*** RANGE PARSING ***/
+
+/// An inclusive range of byte offsets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// The value of the `Content-Range` header for this range of a representation `total` bytes
+    /// long
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+/// What a `Range` header asks for once it's resolved against the length of a file
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    /// The header is missing, malformed or not worth honoring, so send the whole thing
+    Full,
+    /// Send these ranges, sorted and with overlaps merged
+    Partial(Vec<ByteRange>),
+    /// None of the requested ranges overlap the file (416)
+    Unsatisfiable,
+}
+
+impl RangeRequest {
+    /// Resolves the value of a `Range` header against a representation `len` bytes long
+    /// (RFC 7233 §2.1)
+    pub fn parse(header: &str, len: u64) -> Self {
+        let (unit, specs) = match header.find('=') {
+            Some(eq) => (header[..eq].trim(), &header[eq + 1..]),
+            None => return RangeRequest::Full,
+        };
+
+        // Bytes are the only range unit there is
+        if !unit.eq_ignore_ascii_case("bytes") {
+            return RangeRequest::Full;
+        }
+
+        let mut ranges = vec![];
+        for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let dash = match spec.find('-') {
+                Some(dash) => dash,
+                None => return RangeRequest::Full,
+            };
+            let (first, last) = (spec[..dash].trim(), spec[dash + 1..].trim());
+
+            let range = match (parse_offset(first), parse_offset(last)) {
+                // bytes=500-999
+                (Some(start), Some(end)) if start <= end => {
+                    if start >= len {
+                        continue;
+                    }
+                    ByteRange { start, end: end.min(len - 1) }
+                },
+                // bytes=500-
+                (Some(start), None) if last.is_empty() => {
+                    if start >= len {
+                        continue;
+                    }
+                    ByteRange { start, end: len - 1 }
+                },
+                // bytes=-500, i.e. the last 500 bytes
+                (None, Some(suffix)) if first.is_empty() => {
+                    if suffix == 0 || len == 0 {
+                        continue;
+                    }
+                    ByteRange { start: len.saturating_sub(suffix), end: len - 1 }
+                },
+                _ => return RangeRequest::Full,
+            };
+
+            ranges.push(range);
+        }
+
+        if ranges.is_empty() {
+            return RangeRequest::Unsatisfiable;
+        }
+
+        // Merge overlapping ranges so nobody can make us send the same bytes over and over
+        ranges.sort_by_key(|r| r.start);
+        let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+
+        if merged.len() > MAX_RANGES {
+            RangeRequest::Full
+        } else {
+            RangeRequest::Partial(merged)
+        }
+    }
+}
+
+fn parse_offset(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/*** PARTIAL RESPONSES ***/
+
+impl<'r> HttpResponse<'r> {
+    /// Turns a 200 response for a file into a 206 or 416 if the request asks for part of it
+    pub fn with_ranges(self, request: &HttpRequest, path: &Path) -> Self {
+        // Range is only defined for GET (RFC 7233 §3.1)
+        if self.status != StatusCode::Ok || request.method != Method::Get {
+            return self;
+        }
+
+        let header = match request.header("Range") {
+            Some(h) => h,
+            None => return self,
+        };
+
+        let metadata = match fs::metadata(path) {
+            Ok(m) => m,
+            Err(_) => return self,
+        };
+        let len = metadata.len();
+
+        // If-Range says to only send part of the file if it hasn't changed since the client got
+        // the rest of it
+        if let Some(validator) = request.header("If-Range") {
+            if !self.if_range_matches(&validator, &metadata) {
+                return self;
+            }
+        }
+
+        match RangeRequest::parse(&header, len) {
+            RangeRequest::Full => self,
+            RangeRequest::Unsatisfiable => {
+                let content_range = format!("bytes */{}", len);
+                self.with_status(StatusCode::RangeNotSatisfiable)
+                    .with_header("Content-Range", content_range)
+                    .with_body(ResponseBody::Empty)
+            },
+            RangeRequest::Partial(ranges) => match partial_body(path, &ranges, len, &self.content_type()) {
+                Ok((body, content_type, content_range)) => {
+                    let response = self.with_status(StatusCode::PartialContent)
+                        .with_header("Content-Type", content_type);
+                    match content_range {
+                        Some(cr) => response.with_header("Content-Range", cr),
+                        None => response,
+                    }
+                        .with_body(body)
+                },
+                Err(e) => {
+                    println!("Error reading ranges of {}: {}", path.display(), e);
+                    self
+                }
+            }
+        }
+    }
+
+    /// Checks an `If-Range` validator against what this response is about to send
+    ///
+    /// Dates have to match the file's modification time exactly, since they're only accurate to
+    /// the second (RFC 7233 §3.2).
+    fn if_range_matches(&self, validator: &str, metadata: &fs::Metadata) -> bool {
+        let validator = validator.trim();
+        if validator.starts_with('"') || validator.starts_with("W/") {
+            // Weak tags never match
+            return !validator.starts_with("W/")
+                && self.headers.etag() == Some(validator);
+        }
+
+        match (parse_http_date(validator), metadata.modified()) {
+            (Some(date), Ok(modified)) => date == truncate_to_seconds(modified),
+            _ => false,
+        }
+    }
+
+    fn content_type(&self) -> String {
+        self.headers.get("Content-Type")
+            .unwrap_or("application/octet-stream")
+            .to_string()
+    }
+}
+
+/// Builds the body for a 206 along with its `Content-Type` and, for a single range,
+/// `Content-Range`
+fn partial_body(path: &Path, ranges: &[ByteRange], total: u64, content_type: &str)
+    -> io::Result<(ResponseBody, String, Option<String>)>
+{
+    let mut file = File::open(path)?;
+
+    if let [range] = ranges {
+        file.seek(SeekFrom::Start(range.start))?;
+        return Ok((
+            ResponseBody::File(file, range.len()),
+            content_type.to_string(),
+            Some(range.content_range(total))
+        ));
+    }
+
+    // Several ranges go out as a multipart/byteranges body (RFC 7233 Appendix A)
+    let boundary = make_boundary();
+    let mut parts: VecDeque<RangePart> = ranges.iter()
+        .map(|range| RangePart {
+            head: format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary, content_type, range.content_range(total)
+            ).into_bytes(),
+            range: Some(*range),
+        })
+        .collect();
+    parts.push_back(RangePart {
+        head: format!("\r\n--{}--\r\n", boundary).into_bytes(),
+        range: None,
+    });
+
+    let len = parts.iter()
+        .map(|p| p.head.len() as u64 + p.range.map_or(0, |r| r.len()))
+        .sum();
+    let reader = MultipartRanges {
+        file,
+        parts,
+        head: Cursor::new(vec![]),
+        remaining: 0,
+    };
+
+    Ok((
+        ResponseBody::Reader(Box::new(reader), Some(len)),
+        format!("multipart/byteranges; boundary={}", boundary),
+        None
+    ))
+}
+
+/// Makes a multipart boundary that's vanishingly unlikely to show up in the file itself
+fn make_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("SELFISH_BYTERANGES_{:x}", nanos)
+}
+
+struct RangePart {
+    head: Vec<u8>,
+    range: Option<ByteRange>,
+}
+
+/// Reads the parts of a multipart/byteranges body one after the other, seeking through the file
+/// as it goes
+struct MultipartRanges {
+    file: File,
+    parts: VecDeque<RangePart>,
+    head: Cursor<Vec<u8>>,
+    remaining: u64,
+}
+
+impl Read for MultipartRanges {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if (self.head.position() as usize) < self.head.get_ref().len() {
+                return self.head.read(buf);
+            }
+
+            if self.remaining > 0 {
+                let want = buf.len().min(self.remaining as usize);
+                let n = self.file.read(&mut buf[..want])?;
+                self.remaining -= n as u64;
+                return Ok(n);
+            }
+
+            match self.parts.pop_front() {
+                Some(part) => {
+                    if let Some(range) = part.range {
+                        self.file.seek(SeekFrom::Start(range.start))?;
+                        self.remaining = range.len();
+                    }
+                    self.head = Cursor::new(part.head);
+                },
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolves_byte_ranges() {
+        let range = |start, end| ByteRange { start, end };
+
+        assert_eq!(RangeRequest::parse("bytes=0-499", 1000), RangeRequest::Partial(vec![range(0, 499)]));
+        assert_eq!(RangeRequest::parse("bytes=500-", 1000), RangeRequest::Partial(vec![range(500, 999)]));
+        assert_eq!(RangeRequest::parse("bytes=-200", 1000), RangeRequest::Partial(vec![range(800, 999)]));
+        assert_eq!(RangeRequest::parse("bytes=900-2000", 1000), RangeRequest::Partial(vec![range(900, 999)]));
+        assert_eq!(
+            RangeRequest::parse("bytes=500-600, 0-10, 550-700", 1000),
+            RangeRequest::Partial(vec![range(0, 10), range(500, 700)])
+        );
+
+        assert_eq!(RangeRequest::parse("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(RangeRequest::parse("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+
+        // Malformed or unknown ranges are ignored
+        assert_eq!(RangeRequest::parse("bytes=5-1", 1000), RangeRequest::Full);
+        assert_eq!(RangeRequest::parse("bytes=a-b", 1000), RangeRequest::Full);
+        assert_eq!(RangeRequest::parse("pages=1-2", 1000), RangeRequest::Full);
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+
+/// Defines `StatusCode` from a list of `Variant = code, "Reason Phrase"` lines, so each status is
+/// only written down once
+macro_rules! status_codes {
+    ($($variant:ident = $code:expr, $reason:expr;)+) => {
+        /// A response status, with the reason phrase it's sent with
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum StatusCode {
+            $($variant,)+
+        }
+
+        impl StatusCode {
+            /// Looks up a status by its code, for statuses that come from the config
+            pub fn from_code(code: u32) -> Option<Self> {
+                match code {
+                    $($code => Some(StatusCode::$variant),)+
+                    _ => None,
+                }
+            }
+
+            pub fn code(self) -> u32 {
+                match self {
+                    $(StatusCode::$variant => $code,)+
+                }
+            }
+
+            /// The reason phrase from the RFC that defines the status
+            pub fn reason(self) -> &'static str {
+                match self {
+                    $(StatusCode::$variant => $reason,)+
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    // 1xx: Information
+    Continue = 100, "Continue";
+    SwitchingProtocols = 101, "Switching Protocols";
+    Processing = 102, "Processing";
+    EarlyHints = 103, "Early Hints";
+
+    // 2xx: Success
+    Ok = 200, "OK";
+    Created = 201, "Created";
+    Accepted = 202, "Accepted";
+    NonAuthoritativeInformation = 203, "Non-Authoritative Information";
+    NoContent = 204, "No Content";
+    ResetContent = 205, "Reset Content";
+    PartialContent = 206, "Partial Content";
+    MultiStatus = 207, "Multi-Status";
+    AlreadyReported = 208, "Already Reported";
+    ImUsed = 226, "IM Used";
+
+    // 3xx: Redirect
+    MultipleChoices = 300, "Multiple Choices";
+    MovedPermanently = 301, "Moved Permanently";
+    Found = 302, "Found";
+    SeeOther = 303, "See Other";
+    NotModified = 304, "Not Modified";
+    TemporaryRedirect = 307, "Temporary Redirect";
+    PermanentRedirect = 308, "Permanent Redirect";
+
+    // 4xx: Client Errors
+    BadRequest = 400, "Bad Request";
+    Unauthorized = 401, "Unauthorized";
+    PaymentRequired = 402, "Payment Required";
+    Forbidden = 403, "Forbidden";
+    NotFound = 404, "Not Found";
+    MethodNotAllowed = 405, "Method Not Allowed";
+    NotAcceptable = 406, "Not Acceptable";
+    ProxyAuthenticationRequired = 407, "Proxy Authentication Required";
+    RequestTimeout = 408, "Request Timeout";
+    Conflict = 409, "Conflict";
+    Gone = 410, "Gone";
+    LengthRequired = 411, "Length Required";
+    PreconditionFailed = 412, "Precondition Failed";
+    PayloadTooLarge = 413, "Payload Too Large";
+    UriTooLong = 414, "URI Too Long";
+    UnsupportedMediaType = 415, "Unsupported Media Type";
+    RangeNotSatisfiable = 416, "Range Not Satisfiable";
+    ExpectationFailed = 417, "Expectation Failed";
+    ImATeapot = 418, "I'm a teapot";
+    MisdirectedRequest = 421, "Misdirected Request";
+    UnprocessableEntity = 422, "Unprocessable Entity";
+    Locked = 423, "Locked";
+    FailedDependency = 424, "Failed Dependency";
+    TooEarly = 425, "Too Early";
+    UpgradeRequired = 426, "Upgrade Required";
+    PreconditionRequired = 428, "Precondition Required";
+    TooManyRequests = 429, "Too Many Requests";
+    RequestHeaderFieldsTooLarge = 431, "Request Header Fields Too Large";
+    UnavailableForLegalReasons = 451, "Unavailable For Legal Reasons";
+
+    // 5xx: Server Errors
+    InternalServerError = 500, "Internal Server Error";
+    NotImplemented = 501, "Not Implemented";
+    BadGateway = 502, "Bad Gateway";
+    ServiceUnavailable = 503, "Service Unavailable";
+    GatewayTimeout = 504, "Gateway Timeout";
+    HttpVersionNotSupported = 505, "HTTP Version Not Supported";
+    VariantAlsoNegotiates = 506, "Variant Also Negotiates";
+    InsufficientStorage = 507, "Insufficient Storage";
+    LoopDetected = 508, "Loop Detected";
+    NotExtended = 510, "Not Extended";
+    NetworkAuthenticationRequired = 511, "Network Authentication Required";
+}
+
+impl StatusCode {
+    /// Whether the status sends the client to the `Location` header, which not every 3xx does
+    pub fn is_redirect(self) -> bool {
+        matches!(
+            self,
+            StatusCode::MovedPermanently | StatusCode::Found | StatusCode::SeeOther
+                | StatusCode::TemporaryRedirect | StatusCode::PermanentRedirect
+        )
+    }
+
+    /// Whether responses with the status can have a body, which 1xx, 204 and 304 responses never
+    /// do (RFC 7230 §3.3.3)
+    pub fn allows_body(self) -> bool {
+        self.code() >= 200 && self != StatusCode::NoContent && self != StatusCode::NotModified
+    }
+}
+
+/// Writes the status line form, like `404 Not Found`
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::{HttpRequest, HttpResponse};
+
+    #[test]
+    fn names_statuses() {
+        assert_eq!(StatusCode::from_code(416), Some(StatusCode::RangeNotSatisfiable));
+        assert_eq!(StatusCode::from_code(299), None);
+        assert_eq!(StatusCode::PartialContent.code(), 206);
+        assert_eq!(StatusCode::NotFound.to_string(), "404 Not Found");
+        assert!(!StatusCode::NotModified.allows_body() && StatusCode::NotFound.allows_body());
+        assert!(StatusCode::PermanentRedirect.is_redirect() && !StatusCode::NotModified.is_redirect() && !StatusCode::MultipleChoices.is_redirect());
+
+        let request: HttpRequest = "BREW /pot HTTP/1.1\r\nHost: a\r\n\r\n".parse().unwrap();
+        let response = HttpResponse::redirect(&request, StatusCode::SeeOther, "/cup");
+        assert_eq!(
+            response.get_header_string(),
+            "HTTP/1.1 303 See Other\r\nContent-Length: 0\r\nLocation: /cup\r\n\r\n",
+        );
+    }
+}
@@ -1,10 +1,19 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fs;
 use std::fmt;
-use super::utils::*;
+use std::io::{self, Write};
+use super::parser::{RequestParser, ParseError};
+use super::body::ResponseBody;
+use super::encoding::find_precompressed;
+use super::mime::{lookup_extension, lookup_override, sniff_file};
+use super::error_pages;
+use super::method::Method;
+use super::status::StatusCode;
+use super::headers::HeaderMap;
+use super::query::{split_target, Params};
 use serde::{Serialize, Deserialize};
-use std::path::{Path, PathBuf};
-use std::env::current_dir;
+use std::path::Path;
 use std::str::FromStr;
 use colored::*;
 use chrono::prelude::*;
@@ -12,177 +21,314 @@ use chrono::prelude::*;
 /*** REQUEST/RESPONSE LOGIC ***/
 
 #[derive(Debug)]
-pub struct HttpRequest<'r> {
-    pub method: &'r str,
-    pub uri: &'r str,
-    pub version: &'r str,
-    pub headers: HashMap<&'r str, &'r str>,
-    pub body: Option<String>,
+pub struct HttpRequest {
+    pub method: Method,
+    /// The request target as it was sent
+    pub uri: String,
+    /// The percent-decoded path of the target, which is what gets routed
+    pub path: String,
+    /// The query of the target, without the `?` and still percent-encoded
+    pub query: Option<String>,
+    pub version: String,
+    pub headers: HeaderMap,
     pub timestamp: DateTime<Utc>,
 }
 
-impl<'r> HttpRequest<'r> {
-    pub fn new(request_line: &'r str) -> Self {
-        let parts: Vec<&str> = request_line.split(char::is_whitespace).collect();
-        let lines: Vec<&str> = request_line.split("\r\n").collect();
-        let mut headers = HashMap::new();
-        for line in lines {
-            let kvpair: Vec<&str> = line.split(":").collect();
-            if kvpair.len() != 2 {
-                continue;
-            }
-            headers.insert(kvpair[0].trim(), kvpair[1].trim());
-        }
+impl HttpRequest {
+    /// Points the request at another target, like a rewrite rule does
+    pub fn set_target(&mut self, target: String) -> Result<(), ParseError> {
+        let (path, query) = split_target(&target)?;
+        self.uri = target;
+        self.path = path;
+        self.query = query;
 
-        Self {
-            method: parts[0],
-            uri: parts[1],
-            version: parts[2],
-            headers,
-            body: None,
-            timestamp: Utc::now(),
-        }
+        Ok(())
     }
 
-    pub fn to_string(&self) -> String {
-        let mut string = String::new();
-        string.push_str(&format!("{} {} {}\r\n", self.method, self.uri, self.version));
-        for (k, v) in self.headers.iter() {
-            string.push_str(&format!("{}: {}\r\n", k, v));
+    /// The parameters in the query string
+    pub fn query_params(&self) -> Params {
+        self.query.as_deref().map_or_else(Params::default, Params::parse)
+    }
+
+    /// Reads the body as a form, if it was sent as `application/x-www-form-urlencoded`
+    ///
+    /// Forms longer than `MAX_FORM_LEN` are refused with a 413.
+    #[cfg(test)]
+    pub fn form<R: io::Read>(&self, body: R) -> Result<Option<Params>, ParseError> {
+        use std::io::Read;
+        use super::query::MAX_FORM_LEN;
+
+        let is_form = self.headers.content_type().is_some_and(|ct| {
+            ct.media_type == MediaType::Application("x-www-form-urlencoded")
+        });
+        if !is_form {
+            return Ok(None);
         }
-        if let Some(b) = &self.body {
-            string.push_str(&format!("\r\n{}", &b));
+
+        let mut bytes = vec![];
+        body.take(MAX_FORM_LEN + 1).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 > MAX_FORM_LEN {
+            return Err(ParseError::BodyTooLarge(MAX_FORM_LEN));
         }
 
-        string
+        Ok(Some(Params::parse(&String::from_utf8_lossy(&bytes))))
+    }
+
+    /// Looks up a header by name, ignoring case, with the values of a repeated header combined
+    pub fn header(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.headers.get_combined(name)
+    }
+
+    /// Whether the client wants the connection kept open after this request
+    ///
+    /// HTTP/1.1 connections are persistent unless the client says `Connection: close`, while
+    /// HTTP/1.0 connections only persist if the client asks with `Connection: keep-alive`.
+    pub fn wants_keep_alive(&self) -> bool {
+        if self.version == "HTTP/1.0" {
+            self.headers.has_token("Connection", "keep-alive")
+        } else {
+            !self.headers.has_token("Connection", "close")
+        }
     }
 
     pub fn status_string(&self) -> String {
         let color = match self.method {
-            "GET" => "green",
-            "HEAD" => "cyan",
+            Method::Get => "green",
+            Method::Head => "cyan",
             _ => "white"
         };
 
-        format!("[{} | {} {}]", self.timestamp.to_rfc2822().italic(), self.method.color(color), self.uri)
+        format!("[{} | {} {}]", self.timestamp.to_rfc2822().italic(), self.method.as_str().color(color), self.uri)
     }
 }
 
-impl fmt::Display for HttpRequest<'_> {
+impl FromStr for HttpRequest {
+    type Err = ParseError;
+
+    /// Parses a complete request head
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = RequestParser::new();
+        parser.feed(s.as_bytes());
+        parser.parse()?.ok_or(ParseError::UnexpectedEof)
+    }
+}
+
+impl fmt::Display for HttpRequest {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", &self.to_string())
+        write!(f, "{} {} {}\r\n{}", self.method, self.uri, self.version, self.headers)
     }
 }
 
 pub struct HttpResponse<'r> {
     pub version: &'r str,
-    pub status: u32,
-    pub reason: String,
-    pub headers: HashMap<&'r str, String>,
-    pub body: Option<Vec<u8>>,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: ResponseBody,
     pub uri: &'r str,
     pub timestamp: DateTime<Utc>,
+    /// Whether the body is a stand-in page for an error status, which the configured page for
+    /// the status replaces
+    pub error_page: bool,
 }
 
 impl<'r> HttpResponse<'r> {
-    pub fn new(request: &'r HttpRequest, path: &Path, status: u32) -> Self {
-        let reason = HTTP_RESPONSE_STATUSES.get(&status).unwrap();
+    /// Creates a response with a file, or a precompressed sibling of it that `allowed` says is
+    /// fine to serve
+    pub fn new<F: Fn(&Path) -> bool>(request: &'r HttpRequest, path: &Path, status: StatusCode, config: &HttpdConfig, allowed: F) -> Self {
+        let compression = &config.compression.clone().unwrap_or_default();
+
+        // A precompressed sibling (`app.js.br`) is sent as-is in place of the file
+        let (precompressed, sidecar) = find_precompressed(request, path, compression, allowed);
+        let served = sidecar.as_ref().map_or(path, |(_, p)| p.as_path());
+
+        // The file may have been removed since it was routed to
+        let body = match ResponseBody::from_file(served) {
+            Ok(body) => body,
+            Err(e) => {
+                println!("Error opening {}: {}", served.display(), e);
+                return Self::from_io_error(request, &e);
+            }
+        };
 
-        let mut headers = HashMap::new();
-        let content_type = ContentType::parse_from_filename(path);
-        headers.insert("Content-Type", content_type.to_string());
+        // The type comes from the original file, not the `.br` or `.gz` extension
+        let content_type = ContentType::parse_from_filename(path, config.mime_types.as_ref());
 
-        let body = match request.method {
-            "HEAD" => None,
-            "GET" => Some(fs::read(path).unwrap()),
-            _ => None
-        };
+        let mut response = Self::empty(request, status)
+            .with_header("Content-Type", content_type)
+            .with_header("Accept-Ranges", "bytes")
+            .with_body(body)
+            .with_encoding_vary(compression);
+
+        if !precompressed.is_empty() {
+            response = response.with_vary("Accept-Encoding");
+        }
+
+        if let Some((coding, _)) = sidecar {
+            return response
+                .with_header("Content-Encoding", coding.token())
+                .with_validators(request, served, coding);
+        }
+
+        // The coding has to be picked before the validators, since each coding gets its own ETag
+        let coding = response.negotiate_coding(request, compression);
+
+        response
+            .with_validators(request, path, coding)
+            .with_ranges(request, path)
+            .with_coding(coding, compression)
+    }
+
+    /// Creates a response with the built-in page for an error status
+    pub fn error(request: &'r HttpRequest, status: StatusCode) -> Self {
+        let mut response = Self::html(request, status, error_pages::built_in(status).to_string());
+        response.error_page = true;
+        response
+    }
+
+    /// Creates a 404 response
+    pub fn not_found(request: &'r HttpRequest) -> Self {
+        Self::error(request, StatusCode::NotFound)
+    }
 
+    /// Creates a 403 response
+    pub fn forbidden(request: &'r HttpRequest) -> Self {
+        Self::error(request, StatusCode::Forbidden)
+    }
+
+    /// Creates the response for a file that couldn't be read
+    pub fn from_io_error(request: &'r HttpRequest, error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => Self::not_found(request),
+            io::ErrorKind::PermissionDenied => Self::forbidden(request),
+            _ => Self::error(request, StatusCode::InternalServerError),
+        }
+    }
+
+    /// Creates a response without a body
+    pub fn empty(request: &'r HttpRequest, status: StatusCode) -> Self {
         Self {
-            version: request.version,
+            version: &request.version,
             status,
-            reason: reason.to_string(),
-            headers,
-            body,
-            uri: request.uri,
+            headers: HeaderMap::new(),
+            body: ResponseBody::Empty,
+            uri: &request.uri,
             timestamp: Utc::now(),
+            error_page: false,
         }
+            .with_body(ResponseBody::Empty)
     }
 
-    /// Creates a 404 response
-    pub fn not_found(request: &'r HttpRequest) -> Self {
-        let mut headers = HashMap::new();
-        headers.insert("Content-Type", "text/html; charset=utf-8".to_string());
+    /// Creates a response with an HTML page for a body
+    pub fn html(request: &'r HttpRequest, status: StatusCode, html: String) -> Self {
+        Self::empty(request, status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(ResponseBody::Bytes(html.into_bytes()))
+    }
 
-        // For some reason trying to access this causes a panic because it returns None (on MacOS)
-        let status_text = match HTTP_RESPONSE_STATUSES.get(&404) {
-            Some(s) => s.to_string(),
-            None => String::from("Not Found")
-        };
+    /// Replaces the body of an error response with a page of HTML
+    pub fn with_error_page(mut self, html: String) -> Self {
+        self.headers.remove("Content-Encoding");
+        self.with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(ResponseBody::Bytes(html.into_bytes()))
+    }
+
+    /// Creates a response sending the client to another URI
+    pub fn redirect(request: &'r HttpRequest, status: StatusCode, location: &str) -> Self {
+        Self::empty(request, status).with_header("Location", location)
+    }
+
+    /// Creates a response for a request that couldn't be parsed
+    ///
+    /// There's no request to echo the version and URI of, so this assumes HTTP/1.1 and the
+    /// connection is closed afterwards.
+    pub fn from_parse_error(error: &ParseError) -> Self {
+        let status = error.status();
 
         Self {
-            version: request.version,
-            status: 404,
-            reason: status_text,
-            headers,
-            body: Some(fs::read(from_cargo!("src/error_pages/404.html")).unwrap()),
-            uri: request.uri,
+            version: "HTTP/1.1",
+            status,
+            headers: HeaderMap::new(),
+            body: ResponseBody::Empty,
+            uri: "-",
             timestamp: Utc::now(),
+            error_page: false,
         }
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_header("Connection", "close")
+            .with_body(ResponseBody::Bytes(format!("{}: {}\n", status, error).into_bytes()))
     }
 
-    pub fn with_header(mut self, k: &'r str, v: &'r str) -> Self {
-        self.headers.entry(k)
-            .and_modify(|e| { *e = v.to_string(); })
-            .or_insert(v.to_string());
+    /// Sets a header, replacing any value it already had
+    pub fn with_header<K: Into<String>, V: ToString>(mut self, k: K, v: V) -> Self {
+        self.headers.insert(k, v.to_string());
 
         self
     }
 
-    pub fn to_vectored_bytes(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        let mut string = String::new();
+    /// Changes the status, which brings its reason phrase with it
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
 
-        bytes.extend_from_slice({
-            string.push_str(&format!("{} {} {}\r\n", self.version, self.status, &self.reason));
-            for (k, v) in self.headers.iter() {
-                string.push_str(&format!("{}: {}\r\n", k, v));
-            }
-            string.push_str("\r\n");
+        self
+    }
 
-            string.as_bytes()
-        });
+    /// Replaces the body, updating the headers that say how long it is
+    pub fn with_body(mut self, body: ResponseBody) -> Self {
+        self.headers.remove("Content-Length");
+        self.headers.remove("Transfer-Encoding");
+
+        match body.len() {
+            Some(len) => self.headers.insert("Content-Length", len.to_string()),
+            // HTTP/1.0 peers don't understand chunks, so the end of the body is wherever the
+            // connection closes
+            None if self.version == "HTTP/1.0" => {},
+            None => self.headers.insert("Transfer-Encoding", "chunked"),
+        }
+
+        self.body = body;
+        self
+    }
+
+    /// Whether the connection can be kept open after this response, which is the `served`th one
+    /// sent on it
+    pub fn keeps_alive(&self, request: &HttpRequest, served: usize, max_requests: usize) -> bool {
+        request.wants_keep_alive() && served < max_requests && !self.is_close_delimited()
+    }
+
+    /// Whether the peer can only tell where the body ends by the connection closing
+    pub fn is_close_delimited(&self) -> bool {
+        self.body.len().is_none() && !self.headers.contains("Transfer-Encoding")
+    }
 
-        if let Some(body) = &self.body {
-            bytes.extend_from_slice(&body);
+    /// Sends the response to the peer, leaving out the body if it's answering a HEAD request
+    pub fn send<W: Write>(self, stream: &mut W, head_only: bool) -> io::Result<()> {
+        stream.write_all(self.get_header_string().as_bytes())?;
+
+        if !head_only && self.status.allows_body() {
+            let chunked = self.headers.contains("Transfer-Encoding");
+            self.body.write_to(stream, chunked)?;
         }
 
-        bytes
+        stream.flush()
     }
 
     /// Returns a string with the response line and the headers, without the body since that's not
     /// UTF-8 safe
     pub fn get_header_string(&self) -> String {
-        let mut head = format!("{} {} {}\r\n", &self.version, self.status, &self.reason);
-        for (k, v) in self.headers.iter() {
-            head.push_str(&format!("{}: {}\r\n", k, v));
-        }
-        head.push_str("\r\n");
-
-        head
+        format!("{} {}\r\n{}\r\n", self.version, self.status, self.headers)
     }
 
     pub fn status_string(&self) -> String {
-        let status_color = match self.status {
-            100...199 => "cyan",
-            200...299 => "green",
-            300...399 => "yellow",
-            400...499 => "red",
-            500...599 => "magenta",
+        let status_color = match self.status.code() {
+            100..=199 => "cyan",
+            200..=299 => "green",
+            300..=399 => "yellow",
+            400..=499 => "red",
+            500..=599 => "magenta",
             _ => "white",
         };
 
-        format!("[{} | {} {} {}]", &self.timestamp.to_rfc2822().italic(), &format!("{}", self.status).color(status_color).reversed(), &self.reason.color(status_color), &self.uri)
+        format!("[{} | {} {} {}]", &self.timestamp.to_rfc2822().italic(), &format!("{}", self.status.code()).color(status_color).reversed(), &self.status.reason().color(status_color), &self.uri)
     }
 }
 
@@ -194,61 +340,79 @@ impl fmt::Debug for HttpResponse<'_> {
 
 /*** CONTENT TYPES ***/
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ContentType<'c> {
     pub media_type: MediaType<'c>,
     pub parameter: Option<(&'c str, &'c str)>,
 }
 
 impl<'c> ContentType<'c> {
-    pub fn to_string(&self) -> String {
-        if let Some((key, val)) = &self.parameter {
-            format!("{};{}={}", &self.media_type.to_string(), &key, &val)
-        } else {
-            self.media_type.to_string()
+    /// Text types are all sent as UTF-8
+    pub fn from_media_type(media_type: MediaType<'c>) -> Self {
+        let parameter = match media_type {
+            MediaType::Text(_) => Some(("charset", "utf-8")),
+            _ => None,
+        };
+
+        Self {
+            media_type,
+            parameter
         }
     }
 
-    pub fn parse_from_filename(file: &Path) -> Self {
+    /// Parses a `Content-Type` value, keeping its first parameter if it has any
+    pub fn parse(content_type: &'c str) -> Option<Self> {
+        let mut parts = content_type.split(';');
+        let media_type = MediaType::parse(parts.next()?)?;
+        let parameter = parts
+            .filter_map(|p| {
+                let eq = p.find('=')?;
+                Some((p[..eq].trim(), p[eq + 1..].trim()))
+            })
+            .next();
+
+        Some(Self {
+            media_type,
+            parameter
+        })
+    }
+
+    /// Works out the type of a file from its extension, or from its contents if it doesn't have
+    /// one
+    ///
+    /// `overrides` is the `mime_types` map from the config, which wins over the built-in registry.
+    pub fn parse_from_filename(file: &Path, overrides: Option<&'c HashMap<String, String>>) -> Self {
         use self::MediaType::*;
         if file.is_dir() {
-            return Self {
-                media_type: Text("html"),
-                parameter: Some(("charset", "utf-8"))
-            };
+            return Self::from_media_type(Text("html"));
         }
 
-        let media_type = match file.extension() {
-            Some(ext) => {
-                let extension = ext.to_str().unwrap();
-                match extension {
-                    "html" | "htm" => Text("html"),
-                    "js" => Text("javascript"),
-                    "css" => Text("css"),
-                    "ico" | "cur" => Image("x-icon"),
-                    "jpg" | "jpeg" | "jfif" | "pjpeg" | "pjp" => Image("jpeg"),
-                    "png" => Image("png"),
-                    "svg" => Image("svg+xml"),
-                    "json" => Application("json"),
-                    "woff" => Font("woff"),
-                    "woff2" => Font("woff2"),
-                    _ => Application("octet-stream"),
-                }
-            },
-            None => Text("plain")
+        let extension = match file.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => ext,
+            None => {
+                let media_type = sniff_file(file).unwrap_or(Application("octet-stream"));
+                return Self::from_media_type(media_type);
+            }
         };
 
-        let parameter = match media_type {
-            Text("html") => Some(("charset", "utf-8")),
-            _ => None
-        };
+        if let Some(content_type) = overrides.and_then(|o| lookup_override(o, extension)) {
+            return content_type;
+        }
 
-        Self {
-            media_type,
-            parameter
+        Self::from_media_type(lookup_extension(extension).unwrap_or(Application("octet-stream")))
+    }
+}
+
+impl fmt::Display for ContentType<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.parameter {
+            Some((key, val)) => write!(f, "{}; {}={}", self.media_type, key, val),
+            None => write!(f, "{}", self.media_type),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MediaType<'m> {
     Application(&'m str),
     Audio(&'m str),
@@ -261,16 +425,57 @@ pub enum MediaType<'m> {
 }
 
 impl<'m> MediaType<'m> {
-    pub fn to_string(&self) -> String {
+    /// Parses the media type out of a `Content-Type` value, ignoring any parameters
+    pub fn parse(content_type: &'m str) -> Option<Self> {
+        use self::MediaType::*;
+        let essence = content_type.split(';').next()?.trim();
+        let slash = essence.find('/')?;
+        let (top, sub) = (&essence[..slash], &essence[slash + 1..]);
+
+        Some(match top.to_ascii_lowercase().as_str() {
+            "application" => Application(sub),
+            "audio" => Audio(sub),
+            "example" => Example(sub),
+            "font" => Font(sub),
+            "image" => Image(sub),
+            "model" => Model(sub),
+            "text" => Text(sub),
+            "video" => Video(sub),
+            _ => return None,
+        })
+    }
+
+    /// Whether the format isn't already compressed, so gzip and friends would make it smaller
+    pub fn is_compressible(&self) -> bool {
+        use self::MediaType::*;
         match self {
-            MediaType::Application(s) => format!("application/{}", &s),
-            MediaType::Audio(s) => format!("audio/{}", &s),
-            MediaType::Example(s) => format!("example/{}", &s),
-            MediaType::Font(s) => format!("font/{}", &s),
-            MediaType::Image(s) => format!("image/{}", &s),
-            MediaType::Model(s) => format!("model/{}", &s),
-            MediaType::Text(s) => format!("text/{}", &s),
-            MediaType::Video(s) => format!("video/{}", &s),
+            Text(_) => true,
+            Application(s) => {
+                let s = s.to_ascii_lowercase();
+                s.ends_with("+json") || s.ends_with("+xml") || [
+                    "json", "javascript", "ecmascript", "x-javascript", "xml", "wasm", "rtf",
+                    "x-sh", "x-tar", "postscript", "vnd.ms-fontobject", "x-font-ttf",
+                ].contains(&s.as_str())
+            },
+            Image(s) => ["svg+xml", "x-icon", "vnd.microsoft.icon", "bmp"].contains(s),
+            Font(s) => ["ttf", "otf", "collection"].contains(s),
+            Model(s) => ["gltf+json", "obj", "stl"].contains(s),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for MediaType<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MediaType::Application(s) => write!(f, "application/{}", s),
+            MediaType::Audio(s) => write!(f, "audio/{}", s),
+            MediaType::Example(s) => write!(f, "example/{}", s),
+            MediaType::Font(s) => write!(f, "font/{}", s),
+            MediaType::Image(s) => write!(f, "image/{}", s),
+            MediaType::Model(s) => write!(f, "model/{}", s),
+            MediaType::Text(s) => write!(f, "text/{}", s),
+            MediaType::Video(s) => write!(f, "video/{}", s),
         }
     }
 }
@@ -287,13 +492,82 @@ pub struct HttpdConfig {
     pub allowed_methods: Vec<String>,
     pub threads: Option<usize>,
     pub owner: Option<ServerOwner>,
-    pub security: Option<ServerSecurity>
+    pub security: Option<ServerSecurity>,
+    pub keep_alive: Option<ServerKeepAlive>,
+    pub autoindex: Option<bool>,
+    pub compression: Option<ServerCompression>,
+    pub mime_types: Option<HashMap<String, String>>,
+    pub symlinks: Option<SymlinkPolicy>,
+    pub deny: Option<Vec<String>>,
+    pub allow: Option<Vec<String>>,
+    pub root: Option<String>,
+    pub index: Option<Vec<String>>,
+    pub caching: Option<ServerCaching>,
+    pub try_files: Option<Vec<String>>,
+    pub spa: Option<bool>,
+    pub netlify: Option<bool>,
+    pub mounts: Option<Vec<ServerMount>>,
+    pub rules: Option<Vec<ServerRule>>,
+    pub error_pages: Option<HashMap<u32, String>>,
+    pub virtual_hosts: Option<Vec<VirtualHost>>,
+    pub watch: Option<bool>,
+    pub live_reload: Option<bool>,
+    pub build: Option<ServerBuild>,
+    pub upload: Option<ServerUpload>,
 }
 
 impl HttpdConfig {
     pub fn new(config_file: &str) -> Self {
         ron::de::from_str(config_file).unwrap()
     }
+
+    /// A config with nothing but the required fields and `fields` (written as they would be in the
+    /// file) set, for tests
+    #[cfg(test)]
+    pub fn for_test(fields: &str) -> Self {
+        Self::new(&format!(r#"#![enable(implicit_some)] HttpdConfig(host: "", port: 0, allowed_methods: [], {})"#, fields))
+    }
+
+    /// The mount for the document root, made out of the top-level settings
+    pub fn root_mount(&self) -> ServerMount {
+        ServerMount {
+            prefix: String::new(),
+            path: self.root.clone().unwrap_or_else(|| ".".to_string()),
+            allowed_methods: Some(self.allowed_methods.clone()),
+            index: self.index.clone(),
+            autoindex: self.autoindex,
+            caching: self.caching.clone(),
+            try_files: self.try_files.clone(),
+            spa: self.spa,
+            netlify: self.netlify,
+        }
+    }
+
+    /// How many requests can be handled at once
+    pub fn threads(&self) -> usize {
+        self.threads.unwrap_or(DEFAULT_THREADS)
+    }
+
+    /// How many requests a connection may make before it's closed
+    ///
+    /// An open connection holds on to a worker even while it's idle, so with only one worker
+    /// connections are closed after every request instead of keeping everyone else waiting.
+    pub fn max_requests_per_connection(&self) -> usize {
+        if self.threads() <= 1 {
+            return 1;
+        }
+
+        self.keep_alive.as_ref()
+            .and_then(|keep_alive| keep_alive.max_requests)
+            .unwrap_or(DEFAULT_KEEP_ALIVE_MAX_REQUESTS)
+    }
+
+    /// Where the TLS private key is, whether or not TLS is turned on
+    pub fn key_file(&self) -> &str {
+        self.security.as_ref()
+            .and_then(|sec| sec.key_file.as_deref())
+            .unwrap_or(from_cargo!("key.pem"))
+    }
 }
 
 impl Default for HttpdConfig {
@@ -314,4 +588,172 @@ pub struct ServerSecurity {
     pub use_tls: bool,
     pub key_file: Option<String>,
     pub cert_file: Option<String>,
-}
\ No newline at end of file
+}
+
+/// How many requests are handled at once by default, which is more than the six connections
+/// browsers open to a host, so a page's assets don't queue behind each other
+pub const DEFAULT_THREADS: usize = 8;
+
+/// How long an idle connection is kept open by default, in seconds
+pub const DEFAULT_KEEP_ALIVE_TIMEOUT: u64 = 5;
+
+/// How many requests a connection serves by default before it's closed
+pub const DEFAULT_KEEP_ALIVE_MAX_REQUESTS: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ServerKeepAlive {
+    pub timeout: Option<u64>,
+    pub max_requests: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ServerCompression {
+    pub enabled: Option<bool>,
+    pub min_size: Option<u64>,
+    pub gzip_level: Option<u32>,
+    pub brotli_level: Option<u32>,
+    pub precompressed: Option<bool>,
+}
+
+/// Which symbolic links under the document root are followed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum SymlinkPolicy {
+    /// Follow every link, as long as it points somewhere inside the root
+    #[default]
+    Follow,
+    /// Only follow links owned by the same user as whatever they point to
+    FollowIfOwnerMatches,
+    /// Never follow links
+    Deny,
+}
+
+/// A site served for a set of host names, with its own documents and settings
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VirtualHost {
+    pub server_names: Vec<String>,
+    pub default: Option<bool>,
+    pub root: String,
+    pub allowed_methods: Option<Vec<String>>,
+    pub index: Option<Vec<String>>,
+    pub autoindex: Option<bool>,
+    pub caching: Option<ServerCaching>,
+    pub try_files: Option<Vec<String>>,
+    pub spa: Option<bool>,
+    pub netlify: Option<bool>,
+    pub mounts: Option<Vec<ServerMount>>,
+    pub rules: Option<Vec<ServerRule>>,
+    pub headers: Option<HashMap<String, String>>,
+    pub error_pages: Option<HashMap<u32, String>>,
+    pub upload: Option<ServerUpload>,
+}
+
+impl VirtualHost {
+    /// The mount for the host's document root
+    pub fn root_mount(&self) -> ServerMount {
+        ServerMount {
+            prefix: String::new(),
+            path: self.root.clone(),
+            allowed_methods: self.allowed_methods.clone(),
+            index: self.index.clone(),
+            autoindex: self.autoindex,
+            caching: self.caching.clone(),
+            try_files: self.try_files.clone(),
+            spa: self.spa,
+            netlify: self.netlify,
+        }
+    }
+}
+
+/// A directory served under a URL prefix, with its own settings for what's in it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerMount {
+    pub prefix: String,
+    pub path: String,
+    pub allowed_methods: Option<Vec<String>>,
+    pub index: Option<Vec<String>>,
+    pub autoindex: Option<bool>,
+    pub caching: Option<ServerCaching>,
+    pub try_files: Option<Vec<String>>,
+    pub spa: Option<bool>,
+    pub netlify: Option<bool>,
+}
+
+/// A redirect, or a rewrite that serves another URI in place of the one asked for
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerRule {
+    pub from: RuleMatch,
+    pub to: String,
+    pub status: Option<u32>,
+    pub rewrite: Option<bool>,
+    pub host: Option<String>,
+    pub scheme: Option<String>,
+}
+
+/// Which request paths a rule is for
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum RuleMatch {
+    /// Just this path
+    Exact(String),
+    /// This path and everything under it
+    Prefix(String),
+    /// Paths matching a regular expression, whose capture groups can be used in `to`
+    Regex(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ServerCaching {
+    pub max_age: Option<u64>,
+    pub immutable: Option<bool>,
+}
+
+/// A command that builds the site from its source, run again whenever the source changes
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerBuild {
+    pub command: String,
+    pub source: String,
+    pub ignore: Option<Vec<String>>,
+    pub debounce: Option<u64>,
+    pub hold: Option<u64>,
+}
+
+/// An endpoint that takes `multipart/form-data` posts and saves the files in them
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerUpload {
+    pub uri: String,
+    pub dir: String,
+    pub max_file_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub overwrite: Option<bool>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn decides_when_to_keep_alive() {
+        let request = |head: &str| head.parse::<HttpRequest>().unwrap();
+        let http11 = request("GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        let closing = request("GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        let http10 = request("GET / HTTP/1.0\r\n\r\n");
+        let http10_keep = request("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n");
+
+        let keeps_alive = |request: &HttpRequest, served| HttpResponse::empty(request, StatusCode::Ok).keeps_alive(request, served, 3);
+        assert!(keeps_alive(&http11, 1) && keeps_alive(&http11, 2));
+        assert!(!keeps_alive(&http11, 3));
+        assert!(!keeps_alive(&closing, 1));
+        assert!(!keeps_alive(&http10, 1) && keeps_alive(&http10_keep, 1));
+
+        // HTTP/1.0 peers can only tell where a body of unknown length ends by the connection closing
+        let streamed = HttpResponse::empty(&http10_keep, StatusCode::Ok).with_body(ResponseBody::Reader(Box::new(Cursor::new(vec![])), None));
+        assert!(!streamed.keeps_alive(&http10_keep, 1, 3));
+
+        let config = HttpdConfig::for_test;
+        assert_eq!(config("").max_requests_per_connection(), DEFAULT_KEEP_ALIVE_MAX_REQUESTS);
+        assert_eq!(config("threads: 1").max_requests_per_connection(), 1);
+        assert_eq!(config("threads: 4").max_requests_per_connection(), DEFAULT_KEEP_ALIVE_MAX_REQUESTS);
+        assert_eq!(config("threads: 4, keep_alive: (max_requests: 7)").max_requests_per_connection(), 7);
+        assert_eq!(config("threads: 1, keep_alive: (max_requests: 7)").max_requests_per_connection(), 1);
+    }
+}
@@ -0,0 +1,40 @@
+(function () {
+    // Saving a file tends to set off a few events at once, so wait for them to settle
+    var timer = null;
+    var cssOnly = true;
+
+    function refreshStylesheets() {
+        var links = document.querySelectorAll("link[rel=stylesheet]");
+        Array.prototype.forEach.call(links, function (link) {
+            var url = new URL(link.href);
+            url.searchParams.set("livereload", Date.now());
+
+            // Swap the new sheet in once it's loaded so the page doesn't flash unstyled
+            var fresh = link.cloneNode();
+            fresh.href = url.toString();
+            fresh.onload = function () { link.remove(); };
+            link.after(fresh);
+        });
+    }
+
+    function apply() {
+        if (cssOnly) {
+            refreshStylesheets();
+        } else {
+            location.reload();
+        }
+
+        timer = null;
+        cssOnly = true;
+    }
+
+    function queue(css) {
+        cssOnly = cssOnly && css;
+        clearTimeout(timer);
+        timer = setTimeout(apply, 100);
+    }
+
+    var source = new EventSource("{{events_uri}}");
+    source.addEventListener("css", function () { queue(true); });
+    source.addEventListener("reload", function () { queue(false); });
+})();
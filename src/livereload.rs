@@ -0,0 +1,117 @@
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use crate::http::{HttpRequest, HttpResponse, StatusCode};
+use crate::http::body::ResponseBody;
+
+/// Where pages listen for changes. It's out of the way of anything a site would have.
+pub const EVENTS_URI: &str = "/__selfserve/livereload";
+
+/// How often an idle event stream gets a comment, which is how streams to closed tabs are noticed
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The script injected into pages
+const SCRIPT: &str = include_str!("livereload.js");
+
+/// Tells every page that's listening when a watched file changes
+#[derive(Default)]
+pub struct LiveReload {
+    subscribers: Mutex<Vec<Sender<Change>>>,
+}
+
+/// What a page should do about a change
+#[derive(Debug, Clone)]
+struct Change {
+    /// `css` to swap its stylesheets, `reload` to reload the whole page
+    event: &'static str,
+    file: String,
+}
+
+impl LiveReload {
+    /// Lets every page know a file has changed
+    pub fn notify(&self, path: &Path) {
+        let is_css = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("css"));
+        let change = Change {
+            event: if is_css { "css" } else { "reload" },
+            file: path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_string()),
+        };
+
+        // Pages that have gone away have dropped their receiver
+        self.subscribers.lock().unwrap().retain(|tx| tx.send(change.clone()).is_ok());
+    }
+
+    /// Tells every page to reload, whatever it's showing
+    pub fn reload_all(&self) {
+        let change = Change { event: "reload", file: String::new() };
+        self.subscribers.lock().unwrap().retain(|tx| tx.send(change.clone()).is_ok());
+    }
+
+    /// Creates the never-ending response for a page listening for changes, as server-sent events
+    pub fn events<'r>(&self, request: &'r HttpRequest) -> HttpResponse<'r> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+
+        let events = Events {
+            changes: rx,
+            // Get the page to reconnect quickly when the server restarts
+            pending: Cursor::new(b"retry: 1000\n\n".to_vec()),
+        };
+
+        HttpResponse::html(request, StatusCode::Ok, String::new())
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-store")
+            .with_body(ResponseBody::Reader(Box::new(events), None))
+    }
+}
+
+/// Adds the live reload script to a page, at the end of the body if it can find it
+pub fn inject(html: &str) -> String {
+    let script = format!("<script>\n{}</script>\n", SCRIPT.replace("{{events_uri}}", EVENTS_URI));
+    let end_of_body = html.to_ascii_lowercase().rfind("</body>");
+
+    match end_of_body {
+        Some(i) => format!("{}{}{}", &html[..i], script, &html[i..]),
+        None => format!("{}{}", html, script),
+    }
+}
+
+/// The body of an event stream, which blocks until there's something to tell the page
+struct Events {
+    changes: Receiver<Change>,
+    pending: Cursor<Vec<u8>>,
+}
+
+impl Read for Events {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if (self.pending.position() as usize) < self.pending.get_ref().len() {
+                return self.pending.read(buf);
+            }
+
+            let message = match self.changes.recv_timeout(PING_INTERVAL) {
+                Ok(change) => format!("event: {}\ndata: {}\n\n", change.event, change.file),
+                Err(RecvTimeoutError::Timeout) => ": ping\n\n".to_string(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            self.pending = Cursor::new(message.into_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn injects_script_before_end_of_body() {
+        let page = inject("<html><body><p>hi</p></BODY></html>");
+        assert!(page.starts_with("<html><body><p>hi</p><script>"));
+        assert!(page.ends_with("</script>\n</BODY></html>"));
+        assert!(page.contains(EVENTS_URI));
+
+        assert!(inject("<p>fragment</p>").starts_with("<p>fragment</p><script>"));
+    }
+}
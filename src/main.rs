@@ -1,8 +1,9 @@
 #[macro_use] extern crate lazy_static;
-#[macro_use] extern crate serde;
+extern crate serde;
 extern crate ron;
 extern crate rustls;
 extern crate percent_encoding;
+extern crate regex;
 
 use std::net::{
     TcpListener,
@@ -11,15 +12,15 @@ use std::net::{
 use std::io::{
     prelude::*,
     BufReader,
+    ErrorKind,
 };
-use std::collections::HashMap;
 use std::time::Duration;
-use std::fs::File;
+use std::thread;
+use std::fs::{self, File};
 use std::sync::{Arc, RwLock};
-use std::borrow::Borrow;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use rustls::{
-    Session,
     ServerSession,
     ServerConfig,
     StreamOwned,
@@ -30,28 +31,44 @@ use rustls::{
     PrivateKey
 };
 
-use percent_encoding::{
-    percent_decode_str
-};
-
 mod thread_pool;
 #[macro_use] mod http;
 mod security;
 mod routing;
+mod autoindex;
+mod vhost;
+mod watch;
+mod livereload;
+mod build;
+mod rewrite;
+mod netlify;
+mod upload;
 
 use crate::thread_pool::ThreadPool;
 use crate::http::{
+    RequestParser,
+    ParseError,
     HttpRequest,
     HttpResponse,
     HttpdConfig,
+    Method,
+    StatusCode,
+    ContentType,
+    MediaType,
+    DEFAULT_KEEP_ALIVE_TIMEOUT,
+    split_target,
 };
-use crate::routing::Router;
-use std::path::PathBuf;
-use std::path::Path;
+use crate::routing::{Router, RouteError, TryFile};
+use crate::vhost::{Site, Sites};
+use crate::livereload::LiveReload;
+use crate::build::{Builder, BuildState};
+use crate::rewrite::Rewrite;
+use crate::upload::{Uploaded, UploadError};
+use std::path::{Path, PathBuf};
 
 enum Stream {
     Insecure(TcpStream),
-    Secure(StreamOwned<ServerSession, TcpStream>)
+    Secure(Box<StreamOwned<ServerSession, TcpStream>>)
 }
 
 impl Read for Stream {
@@ -63,6 +80,31 @@ impl Read for Stream {
     }
 }
 
+impl Stream {
+    /// The host name the client asked for in the TLS handshake
+    fn sni_hostname(&self) -> Option<&str> {
+        match self {
+            Stream::Insecure(_) => None,
+            Stream::Secure(s) => s.sess.get_sni_hostname(),
+        }
+    }
+
+    /// The scheme of URLs served over the stream
+    fn scheme(&self) -> &'static str {
+        match self {
+            Stream::Insecure(_) => "http",
+            Stream::Secure(_) => "https",
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Duration) -> std::io::Result<()> {
+        match self {
+            Stream::Insecure(s) => s.set_read_timeout(Some(timeout)),
+            Stream::Secure(s) => s.sock.set_read_timeout(Some(timeout)),
+        }
+    }
+}
+
 impl Write for Stream {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         match self {
@@ -81,7 +123,6 @@ impl Write for Stream {
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    let cwd = std::env::current_dir().unwrap();
 
     let config = Arc::new(match args.get(1) {
         Some(a) => HttpdConfig::new(&std::fs::read_to_string(a).unwrap()),
@@ -89,129 +130,524 @@ fn main() {
     });
 
     println!("{:#?}", config);
-    let listener = TcpListener::bind(&format!("{}:{}", config.host, config.port)).unwrap();
-    let protocol = if let Some(sec) = &config.security {
-        if sec.use_tls {
-            "https"
-        } else {
-            "http"
+    let listener = TcpListener::bind(format!("{}:{}", config.host, config.port)).unwrap();
+
+    // The TLS configuration is the same for every connection, so it only needs loading once
+    let tls_config = load_tls_config(&config);
+    let protocol = if tls_config.is_some() { "https" } else { "http" };
+
+    println!("Starting server at {}://{}", protocol, listener.local_addr().unwrap());
+    let sites = Sites::from_config(&config);
+    for site in sites.iter() {
+        if !site.names.is_empty() {
+            println!("Hosting {}", site.names.join(", "));
+        }
+        for mount in site.router.read().unwrap().mounts() {
+            println!("Mounting {:?}", mount);
         }
+        if let Some(upload) = &site.upload {
+            println!("Taking uploads at {:?}", upload);
+        }
+    }
+
+    if config.threads() <= 1 && config.keep_alive.is_some() {
+        println!("Keep-alive is off with only one thread, since an idle connection would hold up everyone else");
+    }
+    let pool = ThreadPool::new(config.threads());
+    let queued = pool.queued();
+    let sites = Arc::new(sites);
+
+    let live_reload = if config.live_reload.unwrap_or(false) {
+        Some(Arc::new(LiveReload::default()))
     } else {
-        "http"
+        None
     };
 
-    println!("Starting server at {}://{}", protocol, listener.local_addr().unwrap());
-    println!("Mounting on {}", cwd.display());
-    let pool = ThreadPool::new(config.threads.unwrap_or(1));
-    let router = Arc::new(RwLock::new(Router::default_from_directory(&cwd)));
+    // Build the site before serving it, and again whenever its source changes
+    let builder = config.build.clone().and_then(|settings| {
+        match Builder::start(settings, live_reload.clone()) {
+            Ok(builder) => Some(builder),
+            Err(e) => {
+                println!("Error watching the build source: {}", e);
+                None
+            }
+        }
+    });
+
+    // Keep the routes in step with the files as they change
+    if config.watch.unwrap_or(true) || live_reload.is_some() {
+        if let Err(e) = watch::watch_sites(sites.clone(), live_reload.clone()) {
+            println!("Error watching for changes: {}", e);
+        }
+    }
 
     for stream in listener.incoming() {
         let stream = stream.unwrap();
         let cfg = config.clone();
-        let rtr = router.clone();
-        pool.execute(move || handle_connection(stream, cfg, rtr));
+        let sts = sites.clone();
+        let tls = tls_config.clone();
+        let lr = live_reload.clone();
+        let bld = builder.clone();
+        let q = queued.clone();
+        pool.execute(move || handle_connection(stream, cfg, sts, tls, lr, bld, q));
     }
 }
 
 fn handle_connection(
-    mut socket: TcpStream,
+    socket: TcpStream,
     config: Arc<HttpdConfig>,
-    router: Arc<RwLock<Router>>
+    sites: Arc<Sites>,
+    tls_config: Option<Arc<ServerConfig>>,
+    live_reload: Option<Arc<LiveReload>>,
+    builder: Option<Arc<Builder>>,
+    queued: Arc<AtomicUsize>,
 ) {
-    // A buffer for holding the HTTP request
-    let mut buffer = [0; 1048];
-
-    // Check the config file for whether or not this request should be served over TLS
-    let is_secure = match config.security.borrow() {
-        Some(sec) => sec.use_tls,
-        None => false
-    };
+    let keep_alive = config.keep_alive.clone().unwrap_or_default();
+    let timeout = keep_alive.timeout.unwrap_or(DEFAULT_KEEP_ALIVE_TIMEOUT);
+    let idle_timeout = Duration::from_secs(timeout.max(1));
+    let max_requests = config.max_requests_per_connection();
+
+    // Idle connections get dropped once the timeout passes without the peer sending anything
+    if let Err(e) = socket.set_read_timeout(Some(idle_timeout)) {
+        println!("Error setting connection timeout: {}", e);
+        return;
+    }
 
     // Wrap the socket in a secure stream if it should be served over TLS
-    let mut stream = if is_secure {
-        // Set up the TLS configurations
-        // TODO: This is slow and I should manage sessions rather than remake them each time
-        let mut tls_cfg = ServerConfig::new(
-            AllowAnyAnonymousOrAuthenticatedClient::new(
-                RootCertStore::empty()
-            )
-        );
-
-        tls_cfg.key_log = Arc::new(KeyLogFile::new());
-        let certs = load_certs(
-            &config.security.clone().unwrap()
-                .cert_file.unwrap_or(from_cargo!("cert.pem").to_string())
-        );
-        let key = load_key(
-            &config.security.clone().unwrap()
-                .key_file.unwrap_or(from_cargo!("key.pem").to_string())
-        );
-        tls_cfg.set_single_cert(certs, key).unwrap();
-
-        let mut session = ServerSession::new(&Arc::new(tls_cfg));
-        let mut s = StreamOwned::new(session, socket);
-
-        Stream::Secure(s)
-    } else {
-        Stream::Insecure(socket)
+    let mut stream = match tls_config {
+        Some(cfg) => Stream::Secure(Box::new(StreamOwned::new(ServerSession::new(&cfg), socket))),
+        None => Stream::Insecure(socket),
     };
 
-    // Read the request from the peer into the buffer
-    stream.read(&mut buffer).unwrap();
+    // Create the string for the Server header field
+    #[cfg(target_os="linux")] let os = "Linux";
+    #[cfg(target_os="macos")] let os = "MacOS";
+    #[cfg(target_os="windows")] let os = "Windows";
+    #[cfg(not(any(target_os="linux", target_os="macos", target_os="windows")))] let os = "Unknown";
+    let server_string = format!("Selfish Server v. {} ({})", env!("CARGO_PKG_VERSION"), os);
+
+    // The parser holds on to anything sent after the current request, so pipelined requests are
+    // picked up (and answered) in the order they arrived
+    let mut parser = RequestParser::new();
+    let mut served = 0;
+
+    loop {
+        // Read the request head from the peer, giving the worker up between requests if other
+        // connections are waiting for one
+        let next = if served == 0 {
+            parser.read_from(&mut stream)
+        } else {
+            next_request(&mut parser, &mut stream, idle_timeout, &queued)
+        };
+        let mut request = match next {
+            Ok(Some(request)) => request,
+            // The peer hung up or went idle, so there's nobody to answer
+            Ok(None) | Err(ParseError::UnexpectedEof) => return,
+            Err(ParseError::Io(ref e)) if is_timeout(e) => return,
+            Err(ParseError::Io(e)) => {
+                println!("Error reading request: {}", e);
+                return;
+            },
+            Err(e) => {
+                send_parse_error(&mut stream, &e);
+                return;
+            }
+        };
+
+        println!("{}", &request.status_string());
+        served += 1;
+
+        // Whatever's built is stale while a build runs, so hold off doing anything with the request
+        // until it's done
+        let site = pick_site(&request, &stream, &sites);
+        let build_state = builder.as_ref().map(|builder| (builder, builder.wait()));
+        let build_ready = build_state.as_ref().is_none_or(|(_, state)| *state == BuildState::Ready);
+
+        // Uploads are saved as they're read, so they're only taken once they'd be answered with
+        // anything but an error. Nothing else serves the body yet, but it still has to come off
+        // the stream
+        let upload = site.filter(|site| build_ready && allows_method(&request, &config, &site.router))
+            .and_then(|site| site.upload.as_ref())
+            .filter(|upload| upload.accepts(&request));
+        let mut uploaded: Option<Result<Uploaded, UploadError>> = None;
+        let body_read = parser.body(&request, &mut stream).and_then(|mut body| {
+            if let Some(upload) = upload {
+                let result = upload.receive(&request, &mut body);
+                let finished = result.is_ok();
+                uploaded = Some(result);
+                // A refused upload is left partway through, and its connection gets closed instead
+                // of reading however much more there is
+                if !finished {
+                    return Ok(0);
+                }
+            }
+            body.discard().map_err(ParseError::from)
+        });
+        match body_read {
+            Ok(_) => {},
+            Err(ParseError::Io(e)) => {
+                println!("Error reading request body: {}", e);
+                return;
+            },
+            Err(e) => {
+                send_parse_error(&mut stream, &e);
+                return;
+            }
+        }
+
+        // Event streams never end, so they get a thread of their own instead of holding up one
+        // from the pool
+        if let Some(live_reload) = live_reload.as_ref().filter(|_| request.path == livereload::EVENTS_URI) {
+            let live_reload = live_reload.clone();
+            thread::spawn(move || {
+                let response = live_reload.events(&request)
+                    .with_header("Server", &server_string)
+                    .with_header("Connection", "close");
+                println!("{}", &response.status_string());
+                let _ = response.send(&mut stream, request.method == Method::Head);
+            });
+            return;
+        }
+
+        let keep_alive_string = format!("timeout={}, max={}", timeout, max_requests.saturating_sub(served));
+
+        // The site's rules run before anything's routed, and can send the client elsewhere or
+        // serve another URI in place of the one asked for
+        let host = request.headers.host().or(stream.sni_hostname());
+        let redirect = match site.and_then(|site| site.rules.apply(&request.path, request.query.as_deref(), host, stream.scheme())) {
+            Some(Rewrite::Redirect(status, location)) => Some((status, location)),
+            Some(Rewrite::Rewrite(uri)) => {
+                println!("Rewriting {} to {}", request.uri, uri);
+                if let Err(e) = request.set_target(uri) {
+                    send_parse_error(&mut stream, &e);
+                    return;
+                }
+                None
+            },
+            None => None,
+        };
+
+        let error_pages = site.map_or(&sites.error_pages, |site| &site.error_pages);
+        let mut response = match (site, build_state, uploaded.as_ref()) {
+            // Methods nobody's heard of can't be allowed anywhere
+            _ if !request.method.is_known() => {
+                error_pages.apply(HttpResponse::error(&request, StatusCode::NotImplemented))
+            },
+            // Whatever's built is stale, so don't serve it if the build's still going...
+            (_, Some((_, BuildState::Building)), _) => error_pages.apply(HttpResponse::error(&request, StatusCode::ServiceUnavailable))
+                .with_header("Retry-After", "1"),
+            // ...or show what went wrong instead if it didn't work
+            (_, Some((builder, BuildState::Failed(status, output))), _) => {
+                let page = builder.error_page(&status, &output);
+                let page = if live_reload.is_some() { livereload::inject(&page) } else { page };
+                HttpResponse::html(&request, StatusCode::InternalServerError, page).with_header("Cache-Control", "no-store")
+            },
+            (Some(site), _, Some(uploaded)) => site.customize(upload::respond(&request, uploaded)),
+            (Some(site), _, None) => site.customize(match &redirect {
+                Some((status, location)) => HttpResponse::redirect(&request, *status, location),
+                None => respond(&request, &config, &site.router),
+            }),
+            (None, _, _) => error_pages.apply(HttpResponse::error(&request, StatusCode::MisdirectedRequest)),
+        }
+            .with_header("Server", &server_string);
+
+        let keep_open = response.keeps_alive(&request, served, max_requests)
+            && !uploaded.as_ref().is_some_and(|result| result.is_err());
+
+        response = if !keep_open {
+            response.with_header("Connection", "close")
+        } else if request.version == "HTTP/1.0" {
+            // HTTP/1.0 connections close by default, so staying open has to be spelled out
+            response.with_header("Connection", "keep-alive")
+                .with_header("Keep-Alive", &keep_alive_string)
+        } else {
+            response
+        };
+
+        println!("{}", &response.status_string());
+
+        // Send the response to the peer
+        if let Err(e) = response.send(&mut stream, request.method == Method::Head) {
+            println!("Error sending response: {}", e);
+            return;
+        }
 
-    let request_line = String::from_utf8_lossy(&mut buffer);
-    let request = HttpRequest::new(&request_line);
+        if !keep_open {
+            return;
+        }
+    }
+}
 
-    println!("{}", &request.status_string());
+/// Picks the site a request is for by its `Host` header, or the name sent with TLS SNI if it
+/// doesn't have one
+///
+/// Returns nothing if there's no site for the host, or if the host and the SNI name are for
+/// different sites, since the connection was then set up for a different site (RFC 7540 §9.1.2).
+fn pick_site<'s>(request: &HttpRequest, stream: &Stream, sites: &'s Sites) -> Option<&'s Site> {
+    let sni = stream.sni_hostname();
+    let site = sites.for_host(request.headers.host().or(sni))?;
+
+    match sni {
+        Some(name) if !std::ptr::eq(sites.for_host(Some(name))?, site) => None,
+        _ => Some(site),
+    }
+}
 
-    // Get the corresponding path if it exists
-    let mut path = {
+/// Works out the response to a request, with whatever headers the `_headers` file of its mount adds
+fn respond<'r>(request: &'r HttpRequest, config: &HttpdConfig, router: &RwLock<Router>) -> HttpResponse<'r> {
+    let mut response = serve(request, config, router);
+    let headers = router.read().unwrap().headers_for(&request.path);
+
+    // The rules' values take the place of any the response already had
+    for (name, _) in headers.iter() {
+        response.headers.remove(name);
+    }
+    for (name, value) in headers.iter() {
+        response.headers.append(name, value);
+    }
+
+    response
+}
+
+/// Finds what to send for a request and makes the response
+fn serve<'r>(request: &'r HttpRequest, config: &HttpdConfig, router: &RwLock<Router>) -> HttpResponse<'r> {
+    // `OPTIONS *` asks about the server as a whole, which is what the root mount allows
+    let is_asterisk = request.uri == "*";
+    if is_asterisk && request.method != Method::Options {
+        return HttpResponse::error(request, StatusCode::BadRequest);
+    }
+
+    // Get the settings of the mount the URI is in, and what it says to look for
+    let (mount, try_files) = {
         let route_reader = router.read().unwrap();
-        route_reader.route_to(request.uri)
+        let path = if is_asterisk { "/" } else { &request.path };
+        match route_reader.mount_for(path) {
+            Some(m) => (m.settings.clone(), m.try_files(&request.path)),
+            None => return HttpResponse::not_found(request),
+        }
     };
 
-    // If it doesn't exist, try finding the file on disk
-    if path.is_none() {
-        let new_path = percent_decode_str(request.uri)
-            .decode_utf8_lossy()
-            .trim_left_matches("/")
-            .to_string();
+    let allowed = mount.allowed_methods.as_ref().unwrap_or(&config.allowed_methods);
+    if request.method == Method::Options {
+        return HttpResponse::empty(request, StatusCode::Ok).with_header("Allow", allow_header(allowed));
+    }
+    if !allowed.iter().any(|method| method == request.method.as_str()) {
+        return HttpResponse::error(request, StatusCode::MethodNotAllowed).with_header("Allow", allow_header(allowed));
+    }
 
-        let mut route_writer = router.write().unwrap();
-        path = route_writer.route_to_new(request.uri, Path::new(&new_path));
+    // Go through the list until something's there, or just look for what was asked for
+    let has_try_files = !try_files.is_empty();
+    let try_files = if has_try_files { try_files } else { vec![TryFile::Uri(request.path.clone())] };
+    let mut found = None;
+    for try_file in try_files {
+        let uri = match try_file {
+            TryFile::Uri(uri) => uri,
+            TryFile::Status(StatusCode::NotFound) => break,
+            TryFile::Status(status) => return HttpResponse::error(request, status),
+        };
+
+        match find(&uri, router) {
+            // Like nginx, only entries ending in a slash can be directories
+            Ok(Some(pb)) if has_try_files && pb.is_dir() && !uri.ends_with('/') => continue,
+            Ok(Some(pb)) => {
+                found = Some((uri, pb));
+                break;
+            },
+            Ok(None) => continue,
+            Err(RouteError::Forbidden(reason)) => {
+                println!("Refusing to serve {}: {}", uri, reason);
+                return HttpResponse::forbidden(request);
+            },
+            Err(RouteError::Denied(reason)) => println!("Hiding {}: {}", uri, reason),
+            Err(RouteError::NotFound) => {},
+        }
     }
 
-    // Create the string for the Server header field
-    #[cfg(target_os="linux")] let os = "Linux";
-    #[cfg(target_os="macos")] let os = "MacOS";
-    #[cfg(target_os="windows")] let os = "Windows";
-    #[cfg(not(any(target_os="linux", target_os="macos", target_os="windows")))] let os = "Unknown";
-    let server_string = format!("Selfish Server v. {} ({})", env!("CARGO_PKG_VERSION"), os);
+    let mut status = StatusCode::Ok;
+
+    // The mount's `_redirects` file gets a say in anything that isn't there, and in what is if its
+    // rule is forced
+    let redirect = router.read().unwrap()
+        .mount_for(&request.path)
+        .and_then(|m| m.redirect_for(&request.path, &request.query_params(), found.is_some()));
+    match redirect {
+        Some(redirect) if redirect.is_redirect() => {
+            let location = redirect.location(request.query.as_deref());
+            return HttpResponse::redirect(request, redirect.status, &location);
+        },
+        // Anything else serves the target in place of the request, with the rule's status. Like
+        // the site's rewrites, it's a target, so any query of its own is split off before routing
+        Some(redirect) => {
+            found = match split_target(&redirect.to).map(|(path, _)| (find(&path, router), path)) {
+                Ok((Ok(Some(pb)), path)) => Some((path, pb)),
+                _ => None,
+            };
+            status = redirect.status;
+        },
+        None => {},
+    }
+
+    // Relative links only resolve inside a directory (or next to its index) if its URI ends with
+    // a slash
+    let is_dir = found.as_ref().is_some_and(|(uri, pb)| {
+        (pb.is_dir() && *uri == request.path) || uri.strip_suffix('/') == Some(request.path.as_str())
+    });
+    if is_dir && !request.path.ends_with('/') {
+        // The target is sent back the way it came, so it's split again instead of using the
+        // decoded path
+        let location = match request.uri.split_once('?') {
+            Some((path, query)) => format!("{}/?{}", path, query),
+            None => format!("{}/", request.uri),
+        };
+        return HttpResponse::redirect(request, StatusCode::MovedPermanently, &location);
+    }
+
+    let compression = config.compression.clone().unwrap_or_default();
+    let live_reload = config.live_reload.unwrap_or(false);
 
     // Make the response
-    let response = if let Some(pb) = path {
-        HttpResponse::new(
-            &request,
-            &pb,
-            if config.allowed_methods.contains(&request.method.to_string()) {
-                200
-            } else {
-                405
+    match found {
+        Some((uri, pb)) if pb.is_dir() => {
+            if !mount.autoindex.unwrap_or(true) {
+                return HttpResponse::not_found(request);
             }
-        )
-    } else {
-        HttpResponse::not_found(&request)
+
+            let hidden = |p: &Path| router.read().unwrap().is_denied(&uri, p);
+            match autoindex::render(&pb, &uri, hidden) {
+                Ok(html) if live_reload => HttpResponse::html(request, status, livereload::inject(&html))
+                    .with_compression(request, &compression),
+                Ok(html) => HttpResponse::html(request, status, html).with_compression(request, &compression),
+                Err(e) => {
+                    println!("Error listing {}: {}", pb.display(), e);
+                    HttpResponse::from_io_error(request, &e)
+                }
+            }
+        },
+        // Pages get the live reload script, which means they can't be served straight from the file
+        Some((_, pb)) if live_reload && is_html(&pb, config) => match fs::read_to_string(&pb) {
+            Ok(html) => HttpResponse::html(request, status, livereload::inject(&html))
+                .with_header("Cache-Control", "no-store")
+                .with_compression(request, &compression),
+            Err(e) => {
+                println!("Error reading {}: {}", pb.display(), e);
+                HttpResponse::from_io_error(request, &e)
+            }
+        },
+        Some((uri, pb)) => HttpResponse::new(request, &pb, status, config, |p| router.read().unwrap().allows(&uri, p))
+            .with_cache_control(&mount.caching.unwrap_or_default()),
+        None => HttpResponse::not_found(request),
     }
-        .with_header("Server", &server_string);
+}
+
+/// Whether the mount a request's path is in allows its method
+fn allows_method(request: &HttpRequest, config: &HttpdConfig, router: &RwLock<Router>) -> bool {
+    let router = router.read().unwrap();
+    let allowed = router.mount_for(&request.path)
+        .and_then(|m| m.settings.allowed_methods.as_ref())
+        .unwrap_or(&config.allowed_methods);
+    allowed.iter().any(|method| method == request.method.as_str())
+}
 
+/// The value of the `Allow` header for a set of methods, which always has OPTIONS since that's
+/// always answered
+fn allow_header(methods: &[String]) -> String {
+    let mut allow: Vec<&str> = methods.iter().map(|m| m.as_str()).collect();
+    if !allow.contains(&"OPTIONS") {
+        allow.push("OPTIONS");
+    }
+
+    allow.join(", ")
+}
+
+/// Finds what's at a URI, looking on disk if the router hasn't seen it or it's gone since
+fn find(uri: &str, router: &RwLock<Router>) -> Result<Option<PathBuf>, RouteError> {
+    let known = router.read().unwrap().route_to(uri);
+    match known {
+        Some(pb) if pb.exists() => Ok(Some(pb)),
+        _ => match router.write().unwrap().route_to_new(uri) {
+            Ok(pb) => Ok(Some(pb)),
+            Err(RouteError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+fn is_html(path: &Path, config: &HttpdConfig) -> bool {
+    ContentType::parse_from_filename(path, config.mime_types.as_ref()).media_type == MediaType::Text("html")
+}
+
+/// Whether a read failed because the peer went quiet for longer than the read timeout
+fn is_timeout(e: &std::io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+/// How often a connection that's between requests checks whether anyone's waiting for its worker
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reads the next request on a connection that's already served one
+///
+/// An open connection holds on to its worker while it's idle, which would leave connections
+/// queued behind it (like the rest of a browser's connections to the site) waiting out the whole
+/// timeout. So it checks every so often whether any are, and gives the worker up to them if it
+/// hasn't received any of the next request yet.
+fn next_request(
+    parser: &mut RequestParser,
+    stream: &mut Stream,
+    timeout: Duration,
+    queued: &AtomicUsize,
+) -> Result<Option<HttpRequest>, ParseError> {
+    stream.set_read_timeout(IDLE_POLL_INTERVAL)?;
+
+    let mut quiet = Duration::ZERO;
+    let next = loop {
+        let buffered = parser.buffered();
+        match parser.read_from(stream) {
+            Err(ParseError::Io(e)) if is_timeout(&e) => {
+                quiet = if parser.buffered() > buffered { Duration::ZERO } else { quiet + IDLE_POLL_INTERVAL };
+                if parser.buffered() == 0 && queued.load(Ordering::SeqCst) > 0 {
+                    break Ok(None);
+                }
+                if quiet >= timeout {
+                    break Err(ParseError::Io(e));
+                }
+            },
+            next => break next,
+        }
+    };
+
+    // Bodies are read with the usual timeout
+    stream.set_read_timeout(timeout)?;
+    next
+}
+
+/// Answers a request that couldn't be parsed
+fn send_parse_error(stream: &mut Stream, error: &ParseError) {
+    let response = HttpResponse::from_parse_error(error);
     println!("{}", &response.status_string());
+    let _ = response.send(stream, false);
+}
 
+/// Sets up the TLS configuration if the config file asks for it
+fn load_tls_config(config: &HttpdConfig) -> Option<Arc<ServerConfig>> {
+    let security = match &config.security {
+        Some(sec) if sec.use_tls => sec,
+        _ => return None,
+    };
+
+    let mut tls_cfg = ServerConfig::new(
+        AllowAnyAnonymousOrAuthenticatedClient::new(
+            RootCertStore::empty()
+        )
+    );
 
-    let mut response_string = response.to_vectored_bytes();
+    tls_cfg.key_log = Arc::new(KeyLogFile::new());
+    let certs = load_certs(
+        security.cert_file.as_deref().unwrap_or(from_cargo!("cert.pem"))
+    );
+    let key = load_key(config.key_file());
+    tls_cfg.set_single_cert(certs, key).unwrap();
 
-    // Send the response to the peer
-    stream.write(&response_string).unwrap();
-    stream.flush().unwrap();
+    Some(Arc::new(tls_cfg))
 }
 
 fn load_certs(filename: &str) -> Vec<Certificate> {
@@ -233,10 +669,123 @@ fn load_key(filename: &str) -> PrivateKey {
         rustls::internal::pemfile::pkcs8_private_keys(&mut reader).unwrap()
     };
 
-    if pkcs8_key.len() > 0 {
-        return pkcs8_key[0].clone();
+    if !pkcs8_key.is_empty() {
+        pkcs8_key[0].clone()
     } else {
-        assert!(rsa_key.len() > 0);
-        return rsa_key[0].clone()
+        assert!(!rsa_key.is_empty());
+        rsa_key[0].clone()
+    }
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allows_methods_per_mount() {
+        let mut config = HttpdConfig::for_test(r#"root: "src",
+            mounts: [(prefix: "/api", path: "src/http", allowed_methods: ["GET", "POST", "OPTIONS"])],"#);
+        config.allowed_methods = vec!["GET".to_string(), "HEAD".to_string()];
+        let router = RwLock::new(Router::from_config(&config));
+        let serve = |head: &str| {
+            let request: HttpRequest = format!("{} HTTP/1.1\r\nHost: a\r\n\r\n", head).parse().unwrap();
+            let response = serve(&request, &config, &router);
+            (response.status.code(), response.headers.get("Allow").map(String::from))
+        };
+        let allow = |methods: &str| Some(methods.to_string());
+
+        assert_eq!(serve("GET /main.rs"), (200, None));
+        assert_eq!(serve("POST /main.rs"), (405, allow("GET, HEAD, OPTIONS")));
+        assert_eq!(serve("OPTIONS /main.rs"), (200, allow("GET, HEAD, OPTIONS")));
+        assert_eq!(serve("OPTIONS *"), (200, allow("GET, HEAD, OPTIONS")));
+        assert_eq!(serve("GET *"), (400, None));
+
+        // The mount's own list replaces the top-level one, rather than adding to it
+        assert_eq!(serve("POST /api/mod.rs"), (200, None));
+        assert_eq!(serve("HEAD /api/mod.rs"), (405, allow("GET, POST, OPTIONS")));
+        assert_eq!(serve("OPTIONS /api/"), (200, allow("GET, POST, OPTIONS")));
+        let post = |uri: &str| format!("POST {} HTTP/1.1\r\nHost: a\r\n\r\n", uri).parse::<HttpRequest>().unwrap();
+        assert!(allows_method(&post("/api/upload"), &config, &router));
+        assert!(!allows_method(&post("/upload"), &config, &router));
+
+        // Methods nobody's heard of get a 501 before they're routed at all
+        assert!(!Method::from("BREW").is_known() && Method::Patch.is_known());
+        assert_eq!(allow_header(&["GET".to_string(), "OPTIONS".to_string()]), "GET, OPTIONS");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rewrites_to_targets_with_a_query() {
+        let dir = std::env::temp_dir().join(format!("selfserve-redirects-{}", std::process::id()));
+        fs::create_dir_all(dir.join("app")).unwrap();
+        fs::write(dir.join("app/index.html"), "app").unwrap();
+        fs::write(dir.join("_redirects"), "/app/* /app/index.html?from=:splat 200\n").unwrap();
+
+        let mut config = HttpdConfig::for_test(&format!("root: {:?}, netlify: true,", dir));
+        config.allowed_methods = vec!["GET".to_string()];
+        let router = RwLock::new(Router::from_config(&config));
+        let request: HttpRequest = "GET /app/settings HTTP/1.1\r\nHost: a\r\n\r\n".parse().unwrap();
+
+        assert_eq!(serve(&request, &config, &router).status.code(), 200);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gives_idle_connections_up_to_queued_ones() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = Stream::Insecure(listener.accept().unwrap().0);
+        let mut parser = RequestParser::new();
+        let queued = AtomicUsize::new(0);
+        let timeout = Duration::from_secs(5);
+
+        client.write_all(b"GET /a HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        let request = next_request(&mut parser, &mut stream, timeout, &queued).unwrap().unwrap();
+        assert_eq!(request.path, "/a");
+
+        // Part of the next request is kept waiting for, but nothing at all isn't
+        queued.store(1, Ordering::SeqCst);
+        client.write_all(b"GET /b HTTP/1.1\r\n").unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(IDLE_POLL_INTERVAL * 3);
+            client.write_all(b"Host: a\r\n\r\n").unwrap();
+            client
+        });
+        let request = next_request(&mut parser, &mut stream, timeout, &queued).unwrap().unwrap();
+        assert_eq!(request.path, "/b");
+        let _client = writer.join().unwrap();
+
+        let started = std::time::Instant::now();
+        assert!(next_request(&mut parser, &mut stream, timeout, &queued).unwrap().is_none());
+        assert!(started.elapsed() < timeout);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn confines_precompressed_siblings() {
+        let dir = std::env::temp_dir().join(format!("selfserve-sidecars-{}", std::process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+        fs::write(root.join("style.css"), "body {}").unwrap();
+        fs::write(root.join("app.js"), "app").unwrap();
+        fs::write(root.join("app.js.br"), "br").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), root.join("style.css.gz")).unwrap();
+
+        let mut config = HttpdConfig::for_test(&format!("root: {:?}, symlinks: Deny, deny: [\"*.br\"],", root));
+        config.allowed_methods = vec!["GET".to_string()];
+        let router = RwLock::new(Router::from_config(&config));
+        let serve = |uri: &str| {
+            let request: HttpRequest = format!("GET {} HTTP/1.1\r\nHost: a\r\nAccept-Encoding: br, gzip\r\n\r\n", uri)
+                .parse()
+                .unwrap();
+            let response = serve(&request, &config, &router);
+            (response.status.code(), response.headers.get("Content-Encoding").map(String::from))
+        };
+
+        // The link and the denied name are left out as if they weren't there
+        assert_eq!(serve("/style.css"), (200, None));
+        assert_eq!(serve("/app.js"), (200, None));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
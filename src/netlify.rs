@@ -0,0 +1,377 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use percent_encoding::{utf8_percent_encode, AsciiSet};
+
+use crate::autoindex::SEGMENT;
+use crate::http::{Params, StatusCode};
+use crate::http::headers::{self, HeaderMap};
+
+/// Characters escaped in what's filled in for a placeholder, which browsers would otherwise read
+/// as part of the URL's structure (backslashes included, since they take them to be slashes)
+pub const PLACEHOLDER: &AsciiSet = &SEGMENT.add(b'\\');
+
+/// The file in a mount's directory with headers to add to responses for its paths
+pub const HEADERS_FILE: &str = "_headers";
+
+/// The file in a mount's directory with its redirects and rewrites
+pub const REDIRECTS_FILE: &str = "_redirects";
+
+/// The rules from a directory's `_headers` and `_redirects` files, as Netlify reads them
+#[derive(Debug, Clone, Default)]
+pub struct NetlifyRules {
+    headers: Vec<HeaderRule>,
+    redirects: Vec<RedirectRule>,
+}
+
+#[derive(Debug, Clone)]
+struct HeaderRule {
+    path: PathPattern,
+    headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone)]
+struct RedirectRule {
+    from: PathPattern,
+    /// Query parameters the request has to have, with the value they have to have or a
+    /// `:placeholder` for whatever it is
+    query: Vec<(String, String)>,
+    to: String,
+    status: StatusCode,
+    /// Whether the rule applies even when there's a file at the path, written `301!`
+    force: bool,
+}
+
+/// What a `_redirects` rule says to do with a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    /// Where to send the client for a 3xx, and what to serve in place of the request otherwise
+    pub to: String,
+    /// 200 for a rewrite, and anything else besides a redirect to serve `to` with that status
+    pub status: StatusCode,
+    /// Whether the request's query goes along to `to`, which it does unless the rule matched on
+    /// the query or has one of its own
+    pub keeps_query: bool,
+}
+
+impl Redirect {
+    pub fn is_redirect(&self) -> bool {
+        self.status.is_redirect()
+    }
+
+    /// Where to send the client, for a request with `query`
+    pub fn location(&self, query: Option<&str>) -> String {
+        match query.filter(|_| self.keeps_query) {
+            Some(query) => format!("{}?{}", self.to, query),
+            None => self.to.clone(),
+        }
+    }
+}
+
+impl NetlifyRules {
+    /// Reads the rules files in a directory, if it has them
+    pub fn load(dir: &Path) -> Self {
+        let read = |name: &str| match fs::read_to_string(dir.join(name)) {
+            Ok(text) => text,
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    println!("Error reading {}: {}", dir.join(name).display(), e);
+                }
+                String::new()
+            }
+        };
+
+        Self::parse(&read(HEADERS_FILE), &read(REDIRECTS_FILE))
+    }
+
+    /// Parses the text of a `_headers` and a `_redirects` file, leaving out anything that can't be
+    /// served locally
+    pub fn parse(headers: &str, redirects: &str) -> Self {
+        Self {
+            headers: parse_headers(headers),
+            redirects: redirects.lines().filter_map(parse_redirect).collect(),
+        }
+    }
+
+    /// Every header the rules add for a path, with the values of a header set by more than one
+    /// rule joined together if they make a list, and kept apart if they don't (like `Set-Cookie`)
+    pub fn headers_for(&self, path: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for rule in self.headers.iter().filter(|rule| rule.path.matches(path).is_some()) {
+            for (name, value) in &rule.headers {
+                match headers.get(name) {
+                    Some(existing) if headers::is_list(name) => {
+                        let combined = format!("{}, {}", existing, value);
+                        headers.insert(name.as_str(), combined);
+                    },
+                    _ => headers.append(name.as_str(), value.as_str()),
+                }
+            }
+        }
+
+        headers
+    }
+
+    /// The first redirect rule that matches a path and query, with its placeholders filled in
+    ///
+    /// Rules don't shadow files that `exists`, unless they're forced.
+    pub fn redirect_for(&self, path: &str, query: &Params, exists: bool) -> Option<Redirect> {
+        self.redirects.iter()
+            .filter(|rule| rule.force || !exists)
+            .find_map(|rule| {
+                let mut params = rule.from.matches(path)?;
+                for (name, value) in &rule.query {
+                    let sent = query.get(name)?;
+                    match value.strip_prefix(':') {
+                        Some(placeholder) => params.push((placeholder.to_string(), sent.to_string())),
+                        None if sent == value => {},
+                        None => return None,
+                    }
+                }
+
+                let to = fill_placeholders(&rule.to, params);
+                let keeps_query = rule.query.is_empty() && !to.contains('?');
+
+                Some(Redirect { to, status: rule.status, keeps_query })
+            })
+    }
+}
+
+/// A path from one of the rules files, which can have `:name` placeholders for whole segments and
+/// a `*` at the end for the rest of the path
+#[derive(Debug, Clone, PartialEq)]
+struct PathPattern {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(String),
+    Splat,
+}
+
+impl PathPattern {
+    fn parse(path: &str) -> Self {
+        Self {
+            segments: path.split('/')
+                .filter(|s| !s.is_empty())
+                .map(|s| match s {
+                    "*" => Segment::Splat,
+                    _ if s.starts_with(':') => Segment::Placeholder(s[1..].to_string()),
+                    _ => Segment::Literal(s.to_string()),
+                })
+                .collect(),
+        }
+    }
+
+    /// What the placeholders (and the splat, as `splat`) match in a path, if the path matches
+    ///
+    /// Trailing slashes don't matter, and a splat matches nothing as well as anything.
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut parts = path.split('/').filter(|s| !s.is_empty());
+        let mut params = vec![];
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Splat => {
+                    params.push(("splat".to_string(), parts.collect::<Vec<_>>().join("/")));
+                    return Some(params);
+                },
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                },
+                Segment::Placeholder(name) => params.push((name.clone(), parts.next()?.to_string())),
+            }
+        }
+
+        match parts.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+}
+
+/// Parses a `_headers` file: each unindented line is a path, and the indented `Name: value` lines
+/// after it are the headers for that path
+fn parse_headers(text: &str) -> Vec<HeaderRule> {
+    let mut rules: Vec<HeaderRule> = vec![];
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if !line.starts_with(char::is_whitespace) {
+            rules.push(HeaderRule { path: PathPattern::parse(trimmed), headers: vec![] });
+            continue;
+        }
+
+        match (rules.last_mut(), trimmed.split_once(':')) {
+            (Some(rule), Some((name, value))) => {
+                rule.headers.push((name.trim().to_string(), value.trim().to_string()));
+            },
+            _ => println!("Ignoring line in {}: {}", HEADERS_FILE, trimmed),
+        }
+    }
+
+    rules
+}
+
+/// Parses a line of a `_redirects` file: `from [name=value...] to [status][!]`
+///
+/// Rules that proxy to other servers, or only apply to some countries, languages or roles, can't
+/// be matched locally and are left out.
+fn parse_redirect(line: &str) -> Option<RedirectRule> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let mut tokens: Vec<&str> = line.split_whitespace().collect();
+    let ignore = |why: &str| {
+        println!("Ignoring rule in {} ({}): {}", REDIRECTS_FILE, why, line);
+        None
+    };
+
+    // Query parameters come between the paths, and the target never looks like one
+    let conditions = tokens.iter()
+        .skip(1)
+        .take_while(|t| t.contains('=') && !t.starts_with('/') && !t.contains("://"))
+        .filter_map(|t| t.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect::<Vec<_>>();
+    tokens.drain(1..1 + conditions.len());
+
+    let (from, to, status) = match tokens[..] {
+        [from, to] => (from, to, None),
+        [from, to, status] => (from, to, Some(status)),
+        _ => return ignore("conditions aren't supported"),
+    };
+
+    let (status, force) = match status {
+        Some(status) => {
+            let force = status.ends_with('!');
+            match status.trim_end_matches('!').parse().ok().and_then(StatusCode::from_code) {
+                Some(status) => (status, force),
+                None => return ignore("invalid status"),
+            }
+        },
+        None => (StatusCode::MovedPermanently, false),
+    };
+
+    if !status.is_redirect() && !status.allows_body() {
+        return ignore("the status can't be sent with a page");
+    }
+    if !to.starts_with('/') && !status.is_redirect() {
+        return ignore("proxying isn't supported");
+    }
+
+    Some(RedirectRule {
+        from: PathPattern::parse(from),
+        query: conditions,
+        to: to.to_string(),
+        status,
+        force,
+    })
+}
+
+/// Replaces `:name` (and `:splat`) in a rule's target with what they matched
+///
+/// The values come from the decoded path and query, so they're encoded again as path segments
+/// (the splat keeping its slashes), and a target that's a path is kept from turning into `//host`.
+fn fill_placeholders(to: &str, mut params: Vec<(String, String)>) -> String {
+    // Longest first, so `:id` doesn't eat the start of `:identity`
+    params.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+
+    // Filled in one pass, so a value with `:name` in it is left as it is
+    let mut target = String::with_capacity(to.len());
+    let mut rest = to;
+    while let Some(start) = rest.find(':') {
+        target.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match params.iter().find(|(name, _)| after.starts_with(name.as_str())) {
+            Some((name, value)) => {
+                let encoded = match name.as_str() {
+                    "splat" => value.split('/')
+                        .map(|segment| utf8_percent_encode(segment, PLACEHOLDER).to_string())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                    _ => utf8_percent_encode(value, PLACEHOLDER).to_string(),
+                };
+                target.push_str(&encoded);
+                rest = &after[name.len()..];
+            },
+            None => {
+                target.push(':');
+                rest = after;
+            },
+        }
+    }
+    target.push_str(rest);
+
+    let is_path = target.starts_with(['/', '\\']);
+    if is_path && !to.starts_with("//") {
+        format!("/{}", target.trim_start_matches(['/', '\\']))
+    } else {
+        target
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_headers_and_redirects() {
+        let rules = NetlifyRules::parse(
+            "# Security\n/*\n  X-Frame-Options: DENY\n  Link: </style.css>; rel=preload\n  Set-Cookie: theme=dark\n\n\
+             /blog/:slug\n  Cache-Control: max-age=60\n  Link: </blog.css>\n  Set-Cookie: seen=1; Path=/blog\n",
+            "/home  /\n/blog/* /news/:splat 302\n/news/:year/:month/:day /archive/:year-:month 301\n\
+             /app/*  /app/index.html  200\n/old /new 301!\n/gone /404.html 404\n\
+             /api/* https://api.example.com/:splat 200\n/store id=:id /blog/:id 301\n/x /y 301 Country=us\n\
+             /stale /y 304\n/choose https://example.com/ 300\n/out/* /:splat 301\n/a/:x/:yy /b/:yy/:x 301\n",
+        );
+
+        assert_eq!(rules.headers_for("/").iter().collect::<Vec<_>>(), vec![
+            ("X-Frame-Options", "DENY"),
+            ("Link", "</style.css>; rel=preload"),
+            ("Set-Cookie", "theme=dark"),
+        ]);
+        let blog = rules.headers_for("/blog/hello/");
+        assert_eq!(blog.get("Link"), Some("</style.css>; rel=preload, </blog.css>"));
+        assert_eq!(blog.get_all("Set-Cookie").collect::<Vec<_>>(), vec!["theme=dark", "seen=1; Path=/blog"]);
+
+        let redirect = |uri: &str, exists| {
+            let (path, query) = uri.split_once('?').map_or((uri, None), |(p, q)| (p, Some(q)));
+            let params = query.map_or_else(Params::default, Params::parse);
+            rules.redirect_for(path, &params, exists).map(|r| (r.status.code(), r.location(query)))
+        };
+        assert_eq!(redirect("/home", false), Some((301, "/".to_string())));
+        assert_eq!(redirect("/blog/2020/post?page=2", false), Some((302, "/news/2020/post?page=2".to_string())));
+        assert_eq!(redirect("/news/2020/05/01", false), Some((301, "/archive/2020-05".to_string())));
+        assert_eq!(redirect("/news/2020/05", false), None);
+        assert_eq!(redirect("/app/settings", false), Some((200, "/app/index.html".to_string())));
+        assert_eq!(redirect("/app/settings", true), None);
+        assert_eq!(redirect("/app/settings?tab=2", false), Some((200, "/app/index.html?tab=2".to_string())));
+        assert_eq!(redirect("/old", true), Some((301, "/new".to_string())));
+        assert_eq!(redirect("/gone", false), Some((404, "/404.html".to_string())));
+        assert_eq!(redirect("/api/users", false), None);
+        assert_eq!(redirect("/store?id=hat&size=9", false), Some((301, "/blog/hat".to_string())));
+        assert_eq!(redirect("/store", false), None);
+        assert_eq!(redirect("/store?id=x%0D%0ASet-Cookie:+a=1", false), Some((301, "/blog/x%0D%0ASet-Cookie:%20a=1".to_string())));
+        assert_eq!(redirect("/store?id=a/b?c", false), Some((301, "/blog/a%2Fb%3Fc".to_string())));
+        assert_eq!(redirect("/x", false), None);
+        assert_eq!(redirect("/stale", false), None);
+        assert_eq!(redirect("/choose", false), None);
+        assert_eq!(redirect("/out/\\\\evil.com", false), Some((301, "/%5C%5Cevil.com".to_string())));
+        assert_eq!(redirect("/a/1/:x", false), Some((301, "/b/:x/1".to_string())));
+        assert_eq!(redirect("/out/:splat/x", false), Some((301, "/:splat/x".to_string())));
+        assert_eq!(redirect("/out/a b/c?d", false), Some((301, "/a%20b/c?d".to_string())));
+    }
+}
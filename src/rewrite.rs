@@ -0,0 +1,241 @@
+use percent_encoding::utf8_percent_encode;
+use regex::{Captures, Regex};
+
+use crate::http::{RuleMatch, ServerRule, StatusCode};
+use crate::netlify::PLACEHOLDER;
+use crate::vhost::normalize_host;
+
+/// The status redirects are sent with if their rule doesn't say
+pub const DEFAULT_REDIRECT_STATUS: StatusCode = StatusCode::MovedPermanently;
+
+/// What the rules say to do with a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rewrite {
+    /// Send the client to another URI, with the given status
+    Redirect(StatusCode, String),
+    /// Serve another URI in place of the one asked for
+    Rewrite(String),
+}
+
+/// The redirect and rewrite rules for a site, tried in order before anything's routed
+#[derive(Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    pattern: Pattern,
+    to: String,
+    /// What to redirect with, or nothing for a rewrite
+    status: Option<StatusCode>,
+    host: Option<String>,
+    scheme: Option<String>,
+}
+
+enum Pattern {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl Rules {
+    /// Compiles the rules from the config, leaving out any that don't make sense
+    pub fn new(rules: &[ServerRule]) -> Self {
+        Self {
+            rules: rules.iter().filter_map(Rule::new).collect(),
+        }
+    }
+
+    /// Works out what the first rule that matches a request says to do with it, if any do
+    ///
+    /// Rules match on the decoded path, like mounts do, so an encoded character can't get a path
+    /// past them. Whatever query the request had is passed along unless the rule's target has one
+    /// of its own.
+    pub fn apply(&self, path: &str, query: Option<&str>, host: Option<&str>, scheme: &str) -> Option<Rewrite> {
+        let host = host.map(normalize_host);
+
+        self.rules.iter()
+            .filter(|rule| rule.host.as_ref().is_none_or(|h| Some(h) == host.as_ref()))
+            .filter(|rule| rule.scheme.as_ref().is_none_or(|s| s.eq_ignore_ascii_case(scheme)))
+            .find_map(|rule| {
+                let mut target = rule.target(path)?;
+                if let Some(query) = query.filter(|_| !target.contains('?')) {
+                    target.push('?');
+                    target.push_str(query);
+                }
+
+                Some(match rule.status {
+                    Some(status) => Rewrite::Redirect(status, target),
+                    None => Rewrite::Rewrite(target),
+                })
+            })
+    }
+}
+
+impl Rule {
+    fn new(rule: &ServerRule) -> Option<Self> {
+        let pattern = match &rule.from {
+            RuleMatch::Exact(path) => Pattern::Exact(path.clone()),
+            RuleMatch::Prefix(prefix) => Pattern::Prefix(prefix.clone()),
+            RuleMatch::Regex(re) => match Regex::new(re) {
+                Ok(re) => Pattern::Regex(re),
+                Err(e) => {
+                    println!("Ignoring rule with invalid pattern {:?}: {}", re, e);
+                    return None;
+                }
+            },
+        };
+
+        let status = if rule.rewrite.unwrap_or(false) {
+            None
+        } else {
+            let status = rule.status.map_or(Some(DEFAULT_REDIRECT_STATUS), StatusCode::from_code);
+            match status.filter(|status| status.is_redirect()) {
+                Some(status) => Some(status),
+                None => {
+                    println!("Ignoring rule for {:?} with status {:?}, which isn't a redirect", rule.from, rule.status);
+                    return None;
+                }
+            }
+        };
+
+        // A rewrite is served by this server, so it can't go anywhere else
+        if status.is_none() && !rule.to.starts_with('/') {
+            println!("Ignoring rewrite for {:?} to {}, which isn't a path", rule.from, rule.to);
+            return None;
+        }
+
+        Some(Self {
+            pattern,
+            to: rule.to.clone(),
+            status,
+            host: rule.host.as_deref().map(normalize_host),
+            scheme: rule.scheme.clone(),
+        })
+    }
+
+    /// Where the rule sends a path, if it matches
+    fn target(&self, path: &str) -> Option<String> {
+        let target = self.expand(path)?;
+
+        // Whatever's filled in from the request mustn't turn a path into `//host`, which browsers
+        // take to be another site (and they read backslashes as slashes too)
+        let is_path = target.starts_with(['/', '\\']);
+        if is_path && !self.to.starts_with("//") {
+            Some(format!("/{}", target.trim_start_matches(['/', '\\'])))
+        } else {
+            Some(target)
+        }
+    }
+
+    /// The rule's target with the parts of the path it matched filled in, encoded again
+    fn expand(&self, path: &str) -> Option<String> {
+        match &self.pattern {
+            Pattern::Exact(exact) if path == exact => Some(self.to.clone()),
+            Pattern::Exact(_) => None,
+            Pattern::Prefix(prefix) => {
+                // Like mounts, `/blog` covers `/blog/post` but not `/blogroll`
+                let rest = path.strip_prefix(prefix.as_str())?;
+                let whole_segment = prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/');
+                whole_segment.then(|| format!("{}{}", self.to, encode_path(rest)))
+            },
+            Pattern::Regex(re) => {
+                re.captures(path).map(|captures| expand_captures(&self.to, &captures))
+            },
+        }
+    }
+}
+
+/// Fills in `$1`, `$name` and `${name}` like `Captures::expand`, but with what each group matched
+/// encoded, since it was matched against the decoded path
+fn expand_captures(to: &str, captures: &Captures) -> String {
+    let mut target = String::new();
+    let mut rest = to;
+
+    while let Some(i) = rest.find('$') {
+        target.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            target.push('$');
+            rest = after;
+            continue;
+        }
+
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.split_once('}') {
+                Some(split) => split,
+                None => ("", rest),
+            },
+            None => {
+                let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+                rest.split_at(end)
+            },
+        };
+        if name.is_empty() {
+            target.push('$');
+            continue;
+        }
+
+        let group = match name.parse::<usize>() {
+            Ok(i) => captures.get(i),
+            Err(_) => captures.name(name),
+        };
+        if let Some(group) = group {
+            target.push_str(&encode_path(group.as_str()));
+        }
+        rest = after;
+    }
+
+    target.push_str(rest);
+    target
+}
+
+/// Encodes each segment of a decoded path, leaving the slashes between them
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PLACEHOLDER).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::{split_target, HttpdConfig};
+
+    #[test]
+    fn applies_the_first_matching_rule() {
+        let config = HttpdConfig::for_test(r#"
+            rules: [
+                (from: Prefix("/"), to: "https://example.com/", host: "www.example.com"),
+                (from: Exact("/old.html"), to: "/new.html", status: 308),
+                (from: Prefix("/blog"), to: "/posts"),
+                (from: Regex("^/users/(?P<id>[0-9]+)$"), to: "/users/${id}.html", rewrite: true),
+                (from: Regex("^(/.+)/$"), to: "$1", status: 302),
+                (from: Prefix("/login"), to: "https://example.com/login", scheme: "http"),
+                (from: Exact("/bad"), to: "/x", status: 200),
+            ],"#);
+        let rules = Rules::new(config.rules.as_deref().unwrap());
+        let apply = |uri, host| {
+            let (path, query) = split_target(uri).unwrap();
+            rules.apply(&path, query.as_deref(), host, "http")
+        };
+
+        assert_eq!(apply("/a?b=c", Some("WWW.example.com:80")), Some(Rewrite::Redirect(StatusCode::MovedPermanently, "https://example.com/a?b=c".into())));
+        assert_eq!(apply("/old.html", None), Some(Rewrite::Redirect(StatusCode::PermanentRedirect, "/new.html".into())));
+        assert_eq!(apply("/blog/2020/hi?page=2", None), Some(Rewrite::Redirect(StatusCode::MovedPermanently, "/posts/2020/hi?page=2".into())));
+        assert_eq!(apply("/blogroll", None), None);
+        assert_eq!(apply("/users/42?tab=1", None), Some(Rewrite::Rewrite("/users/42.html?tab=1".into())));
+        assert_eq!(apply("/docs/", None), Some(Rewrite::Redirect(StatusCode::Found, "/docs".into())));
+        assert_eq!(apply("//evil.com/", None), Some(Rewrite::Redirect(StatusCode::Found, "/evil.com".into())));
+        assert_eq!(apply("/%2F%2Fevil.com/", None), Some(Rewrite::Redirect(StatusCode::Found, "/evil.com".into())));
+        assert_eq!(apply("/\\evil.com/", None), Some(Rewrite::Redirect(StatusCode::Found, "/%5Cevil.com".into())));
+        assert_eq!(apply("/%62log/a%20b%3Fc?d=e", None), Some(Rewrite::Redirect(StatusCode::MovedPermanently, "/posts/a%20b%3Fc?d=e".into())));
+        assert_eq!(apply("/users/4%32", None), Some(Rewrite::Rewrite("/users/42.html".into())));
+        assert_eq!(apply("/blog//evil.com", None), Some(Rewrite::Redirect(StatusCode::MovedPermanently, "/posts//evil.com".into())));
+        assert_eq!(apply("/login", None), Some(Rewrite::Redirect(StatusCode::MovedPermanently, "https://example.com/login".into())));
+        assert_eq!(rules.apply("/login", None, None, "https"), None);
+        assert_eq!(apply("/bad", None), None);
+    }
+}
@@ -3,96 +3,722 @@ use std::collections::HashMap;
 use std::fs;
 use std::fmt;
 
-use percent_encoding::{
-    AsciiSet,
-    CONTROLS,
-    utf8_percent_encode,
-    percent_decode_str
-};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 
+use crate::http::{HttpdConfig, Params, ServerMount, StatusCode, SymlinkPolicy};
+use crate::http::headers::HeaderMap;
+use crate::netlify::{self, NetlifyRules, Redirect};
+
+/// Files by the (percent-decoded) path they're served at
 type Routes = HashMap<String, PathBuf>;
 
-const FRAGMENTS: &AsciiSet = &CONTROLS.add(b' ').add(b'%');
+/// What's never served unless the config says otherwise: dotfiles (`.env`, `.git/`, `.htpasswd`)
+/// and the VCS directories that don't start with a dot
+pub const DEFAULT_DENY: &[&str] = &[".*", "CVS", "_darcs"];
+
+/// Exceptions to the deny rules by default, since ACME challenges live under `.well-known/`
+pub const DEFAULT_ALLOW: &[&str] = &[".well-known"];
+
+/// How many routes a mount remembers before it stops adding the ones it finds on demand, which
+/// are then looked up each time they're asked for
+pub const MAX_ROUTES: usize = 65_536;
+
+/// What `spa: true` stands for: anything that isn't a file or directory gets the app's page
+pub const SPA_TRY_FILES: &[&str] = &["$uri", "$uri/", "/index.html"];
+
+/// Why a URI couldn't be resolved to a path
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteError {
+    /// There's nothing there
+    NotFound,
+    /// The path leads out of the document root, or through a link the symlink policy doesn't
+    /// allow, for the reason given
+    Forbidden(String),
+    /// The path matches a deny rule, for the reason given, and is treated as if it weren't there
+    Denied(String),
+}
+
+/// The index files looked for in a directory by default, in order
+pub const DEFAULT_INDEX: &[&str] = &["index.html", "index.htm"];
+
+/// One of the things to try for a request, out of a mount's `try_files`
+#[derive(Debug, Clone, PartialEq)]
+pub enum TryFile {
+    /// A URI in the mount to serve if there's something there
+    Uri(String),
+    /// A status to respond with, written `=404` and the like, which ends the list
+    Status(StatusCode),
+}
+
+/// A directory served under a URL prefix
+#[derive(Clone)]
+pub struct Mount {
+    /// The URL prefix, without a trailing slash, so the root mount's is empty
+    pub prefix: String,
+    pub settings: ServerMount,
+    root: DocumentRoot,
+    routes: Routes,
+    /// What's in the directory's `_headers` and `_redirects` files, if they're read at all
+    netlify: Option<NetlifyRules>,
+}
+
+impl Mount {
+    fn new(settings: ServerMount, config: &HttpdConfig) -> Self {
+        let prefix = settings.prefix.trim_end_matches('/');
+        let prefix = if prefix.is_empty() || prefix.starts_with('/') {
+            prefix.to_string()
+        } else {
+            format!("/{}", prefix)
+        };
+
+        let mut root = DocumentRoot::new(Path::new(&settings.path), config);
+        let netlify = if settings.netlify.unwrap_or(true) {
+            root.hide_netlify_files = true;
+            Some(NetlifyRules::load(&root.path))
+        } else {
+            None
+        };
+
+        let mut mount = Self {
+            prefix,
+            settings,
+            root,
+            routes: Routes::new(),
+            netlify,
+        };
+
+        let mut routes = Routes::new();
+        _create_directory_routes(&mount.root.path, &format!("{}/", mount.prefix), &mut routes, &mount);
+        mount.routes = routes;
+        mount
+    }
+
+    /// Whether the mount serves a URI, going by whole path segments
+    fn serves(&self, uri: &str) -> bool {
+        uri.starts_with(&self.prefix)
+            && (uri.len() == self.prefix.len() || uri[self.prefix.len()..].starts_with('/'))
+    }
+
+    /// The names of the index files for directories in this mount
+    pub fn index_names(&self) -> Vec<&str> {
+        match &self.settings.index {
+            Some(names) => names.iter().map(|n| n.as_str()).collect(),
+            None => DEFAULT_INDEX.to_vec(),
+        }
+    }
+
+    /// What to try serving for a URI in the mount, in order, with `$uri` replaced by the part
+    /// of the URI after the mount's prefix
+    ///
+    /// This is empty if the mount doesn't have `try_files` (or `spa`) set, in which case only what
+    /// was asked for is served.
+    pub fn try_files(&self, uri: &str) -> Vec<TryFile> {
+        let entries: Vec<&str> = match &self.settings.try_files {
+            Some(entries) => entries.iter().map(|e| e.as_str()).collect(),
+            None if self.settings.spa.unwrap_or(false) => SPA_TRY_FILES.to_vec(),
+            None => return vec![],
+        };
+        let relative = uri.get(self.prefix.len()..).unwrap_or_default();
+
+        entries.iter()
+            .map(|entry| {
+                let status = entry.strip_prefix('=')
+                    .and_then(|code| code.parse().ok())
+                    .and_then(StatusCode::from_code);
+
+                match status {
+                    Some(status) => TryFile::Status(status),
+                    None => {
+                        let path = entry.replace("$uri", relative);
+                        let slash = if path.is_empty() || path.starts_with('/') { "" } else { "/" };
+                        TryFile::Uri(format!("{}{}{}", self.prefix, slash, path))
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// The part of a URI in the mount after its prefix, which is what's matched against the
+    /// rules files
+    fn relative_uri<'u>(&self, uri: &'u str) -> &'u str {
+        uri.get(self.prefix.len()..).filter(|rest| !rest.is_empty()).unwrap_or("/")
+    }
+
+    /// The headers the mount's `_headers` file adds for a URI
+    pub fn headers_for(&self, uri: &str) -> HeaderMap {
+        self.netlify.as_ref()
+            .map_or_else(HeaderMap::new, |rules| rules.headers_for(self.relative_uri(uri)))
+    }
+
+    /// What the mount's `_redirects` file says to do with a URI, if anything, with the target
+    /// made into a URI in the mount
+    ///
+    /// Rules only apply to URIs where something `exists` if they're forced.
+    pub fn redirect_for(&self, uri: &str, query: &Params, exists: bool) -> Option<Redirect> {
+        let mut redirect = self.netlify.as_ref()?.redirect_for(self.relative_uri(uri), query, exists)?;
+        if redirect.to.starts_with('/') {
+            redirect.to = format!("{}{}", self.prefix, redirect.to);
+        }
+
+        Some(redirect)
+    }
+
+    /// The index file of a directory, if it has one that's fine to serve
+    fn find_index(&self, dir: &Path) -> Option<PathBuf> {
+        self.index_names().iter()
+            .map(|name| dir.join(name))
+            .find(|index| index.is_file() && self.root.allows(index))
+    }
+
+    /// The URI a path in the mount is served at, as `_create_directory_routes` would route it
+    fn uri_for(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.root.path).ok()?;
+        let segments: Vec<_> = relative.components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect();
+        Some(format!("{}/{}", self.prefix, segments.join("/")))
+    }
+
+    /// Brings the routes up to date after something was created, removed or renamed at `path`
+    fn update(&mut self, path: &Path) {
+        if !path.starts_with(&self.root.path) {
+            return;
+        }
+
+        if self.netlify.is_some() && self.root.is_netlify_file(path) {
+            self.netlify = Some(NetlifyRules::load(&self.root.path));
+            return;
+        }
+
+        // Forget whatever was at or under the path, and the listing of the directory it's in,
+        // which might have gained or lost its index
+        let parent = path.parent();
+        self.routes.retain(|_, routed| !routed.starts_with(path) && Some(routed.as_path()) != parent);
+
+        // Links are left for route_to_new, same as when the routes were first made
+        let is_link = fs::symlink_metadata(path).map(|m| m.file_type().is_symlink()).unwrap_or(true);
+        if is_link || self.root.check_denied(path).is_err() {
+            return;
+        }
+
+        let uri = match self.uri_for(path) {
+            Some(uri) => uri,
+            None => return,
+        };
+
+        if path.is_dir() {
+            let mut routes = Routes::new();
+            _create_directory_routes(path, &format!("{}/", uri.trim_end_matches('/')), &mut routes, self);
+            self.routes.extend(routes);
+        } else if path.is_file() {
+            self.routes.insert(uri, path.to_owned());
+
+            // A new index takes over its directory
+            let dir = parent.and_then(|dir| Some((self.find_index(dir)?, self.uri_for(dir)?)));
+            if let Some((index, dir_uri)) = dir {
+                self.routes.insert(format!("{}/", dir_uri.trim_end_matches('/')), index);
+            }
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Router {
-    routes: Routes
+    /// Longest prefix first, so the first mount that serves a URI is the most specific one
+    mounts: Vec<Mount>,
 }
 
 impl Router {
-    /// Resolves a URI to a path
+    pub fn mounts(&self) -> &[Mount] {
+        &self.mounts
+    }
+
+    /// Finds the mount a URI belongs to
+    pub fn mount_for(&self, uri: &str) -> Option<&Mount> {
+        self.mounts.iter().find(|m| m.serves(uri))
+    }
+
+    /// Resolves the (percent-decoded) path of a request to a file
     #[inline]
     pub fn route_to(&self, uri: &str) -> Option<PathBuf> {
-        if let Some(pb) = self.routes.get(uri) {
-            Some(pb.clone())
-        } else {
-            None
+        self.mount_for(uri)?.routes.get(uri).cloned()
+    }
+
+    /// Resolves a URI the router hasn't seen yet to a file in its mount, and remembers it if it's
+    /// there
+    ///
+    /// Only the URI a file would be routed at to begin with is remembered, not the other ways of
+    /// writing it (with `.`, `..` or doubled slashes), so clients can't fill the routes with
+    /// aliases. Past [`MAX_ROUTES`] nothing more is remembered at all.
+    pub fn route_to_new(&mut self, uri: &str) -> Result<PathBuf, RouteError> {
+        let mount = match self.mounts.iter_mut().find(|m| m.serves(uri)) {
+            Some(m) => m,
+            None => return Err(RouteError::NotFound),
+        };
+
+        let relative = uri[mount.prefix.len()..].trim_start_matches('/').to_string();
+
+        let mut resolved = match mount.root.confine(Path::new(&relative)) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                // Whatever was routed here before is gone
+                mount.routes.remove(uri);
+                return Err(e);
+            }
+        };
+        if resolved.is_dir() && uri.ends_with('/') {
+            if let Some(index) = mount.find_index(&resolved) {
+                resolved = index;
+            }
         }
+
+        if is_canonical(&uri[mount.prefix.len()..]) && mount.routes.len() < MAX_ROUTES {
+            mount.routes.insert(String::from(uri), resolved.clone());
+        }
+        Ok(resolved)
     }
 
-    #[inline]
-    pub fn route_to_new(&mut self, uri: &str, path: &Path) -> Option<PathBuf> {
-        if path.exists() {
-            self.routes.insert(String::from(uri), path.to_owned());
-            self.route_to(uri)
-        } else {
-            None
+    /// The headers the `_headers` file of the mount a URI belongs to adds for it
+    pub fn headers_for(&self, uri: &str) -> HeaderMap {
+        self.mount_for(uri).map_or_else(HeaderMap::new, |m| m.headers_for(uri))
+    }
+
+    /// Brings the routes of every mount the path is in up to date after something was created,
+    /// removed or renamed there
+    pub fn update(&mut self, path: &Path) {
+        for mount in &mut self.mounts {
+            mount.update(path);
         }
     }
 
-    /// Creates a set of routes for the given directory
-    pub fn default_from_directory(root: &Path) -> Self {
-        let mut routes = Routes::new();
-        _create_directory_routes(root, "/", &mut routes);
+    /// The directories the mounts serve, for watching for changes
+    pub fn roots(&self) -> Vec<PathBuf> {
+        self.mounts.iter().map(|m| m.root.path.clone()).collect()
+    }
+
+    /// Whether a path is hidden by the deny rules of the mount a URI belongs to, for leaving it
+    /// out of directory listings
+    pub fn is_denied(&self, uri: &str, path: &Path) -> bool {
+        self.mount_for(uri).is_none_or(|m| m.root.check_denied(path).is_err())
+    }
+
+    /// Whether a path that didn't come from a URI (like a precompressed sibling of a file) is
+    /// fine to serve for a URI, going by the confinement and deny rules of its mount
+    pub fn allows(&self, uri: &str, path: &Path) -> bool {
+        self.mount_for(uri).is_some_and(|m| m.root.allows(path))
+    }
+
+    /// Creates the routes for a document root and the mounts under it
+    pub fn new(root: ServerMount, mounts: &[ServerMount], config: &HttpdConfig) -> Self {
+        let mut mounts: Vec<Mount> = mounts.iter()
+            .cloned()
+            .chain(std::iter::once(root))
+            .map(|settings| Mount::new(settings, config))
+            .collect();
+        mounts.sort_by_key(|m| std::cmp::Reverse(m.prefix.len()));
+
         Self {
-            routes
+            mounts
         }
     }
+
+    /// Creates the routes for the document root and every mount in the config
+    pub fn from_config(config: &HttpdConfig) -> Self {
+        Self::new(config.root_mount(), config.mounts.as_deref().unwrap_or(&[]), config)
+    }
 }
 
 impl fmt::Debug for Router {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:#?}", self.routes)
+        let mounts: HashMap<&str, &Routes> = self.mounts.iter()
+            .map(|m| (m.prefix.as_str(), &m.routes))
+            .collect();
+        write!(f, "{:#?}", mounts)
     }
 }
 
-fn _create_directory_routes(dir: &Path, prefix: &str, routes: &mut Routes) {
-    // Add the index for the directory, if it exists
-    let indices = ["index.html", "index.htm"];
-    for s in indices.iter() {
-        let index = dir.join(s);
-        if index.exists() {
-            routes.insert(utf8_percent_encode(prefix, FRAGMENTS).to_string(), index);
-            break;
+impl fmt::Debug for Mount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/ -> {}", self.prefix, self.root.path.display())
+    }
+}
+
+/*** CONFINEMENT ***/
+
+/// The directory everything is served out of, and the rules for staying inside it
+#[derive(Clone, Debug)]
+struct DocumentRoot {
+    /// Always canonical, so resolved paths can be checked against it
+    path: PathBuf,
+    symlinks: SymlinkPolicy,
+    deny: DenyList,
+    /// Whether the `_headers` and `_redirects` files at the top are config rather than content
+    hide_netlify_files: bool,
+}
+
+impl DocumentRoot {
+    fn new(path: &Path, config: &HttpdConfig) -> Self {
+        Self {
+            path: fs::canonicalize(path).unwrap_or_else(|_| path.to_owned()),
+            symlinks: config.symlinks.unwrap_or_default(),
+            deny: DenyList::new(config),
+            hide_netlify_files: false,
+        }
+    }
+
+    /// Resolves a path relative to the root, making sure it doesn't lead anywhere outside it
+    ///
+    /// `..` is resolved lexically before anything touches the disk, then the result is
+    /// canonicalized so links pointing out of the root are caught too.
+    fn confine(&self, relative: &Path) -> Result<PathBuf, RouteError> {
+        let mut path = self.path.clone();
+        for component in relative.components() {
+            match component {
+                Component::Normal(part) => {
+                    path.push(part);
+                    self.check_link(&path)?;
+                },
+                Component::CurDir => {},
+                Component::ParentDir => {
+                    if path == self.path {
+                        return Err(RouteError::Forbidden("it climbs out of the document root".to_string()));
+                    }
+                    path.pop();
+                },
+                Component::RootDir | Component::Prefix(_) => {
+                    return Err(RouteError::Forbidden("it's an absolute path".to_string()));
+                },
+            }
+        }
+
+        let canonical = fs::canonicalize(&path).map_err(|_| RouteError::NotFound)?;
+        if !canonical.starts_with(&self.path) {
+            return Err(RouteError::Forbidden(format!("it resolves to {}", canonical.display())));
+        }
+
+        // A link can't be used to get at something denied under another name either
+        self.check_denied(&path)?;
+        self.check_denied(&canonical)?;
+
+        // Keep the name the client asked for, since the type of a file comes from its extension
+        Ok(path)
+    }
+
+    /// Whether a path that's already inside the root is fine to serve, for paths that didn't
+    /// come from a URI
+    fn allows(&self, path: &Path) -> bool {
+        self.check_link(path).is_ok()
+            && self.check_denied(path).is_ok()
+            && fs::canonicalize(path).is_ok_and(|p| p.starts_with(&self.path) && self.check_denied(&p).is_ok())
+    }
+
+    /// Checks a path under the root against the deny rules
+    fn check_denied(&self, path: &Path) -> Result<(), RouteError> {
+        if self.hide_netlify_files && self.is_netlify_file(path) {
+            return Err(RouteError::Denied(format!("{} is a rules file", path.display())));
+        }
+
+        let relative = path.strip_prefix(&self.path).unwrap_or(path);
+        match self.deny.matches(relative, path) {
+            Some(reason) => Err(RouteError::Denied(reason)),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a path is the `_headers` or `_redirects` file at the top of the root
+    fn is_netlify_file(&self, path: &Path) -> bool {
+        [netlify::HEADERS_FILE, netlify::REDIRECTS_FILE].iter().any(|name| path == self.path.join(name))
+    }
+
+    /// Checks a path against the symlink policy, if it's a link at all
+    fn check_link(&self, path: &Path) -> Result<(), RouteError> {
+        let link = match fs::symlink_metadata(path) {
+            Ok(m) if m.file_type().is_symlink() => m,
+            // Anything missing gets caught when the path is canonicalized
+            _ => return Ok(()),
+        };
+
+        match self.symlinks {
+            SymlinkPolicy::Follow => Ok(()),
+            SymlinkPolicy::Deny => {
+                Err(RouteError::Forbidden(format!("{} is a symbolic link", path.display())))
+            },
+            SymlinkPolicy::FollowIfOwnerMatches => match fs::metadata(path) {
+                Ok(target) if same_owner(&link, &target) => Ok(()),
+                Ok(_) => Err(RouteError::Forbidden(format!(
+                    "{} is a symbolic link to something owned by another user",
+                    path.display()
+                ))),
+                Err(_) => Err(RouteError::NotFound),
+            },
+        }
+    }
+}
+
+/*** DENY RULES ***/
+
+/// Glob patterns for files that are never served, like the TLS key and VCS metadata
+///
+/// Patterns without a slash match a name at any depth, like in a `.gitignore`, and a pattern that
+/// matches a directory hides everything in it.
+#[derive(Clone, Debug)]
+struct DenyList {
+    deny: GlobSet,
+    allow: GlobSet,
+    /// The TLS private key is always denied, wherever it is and whatever the rules say
+    key_file: Option<PathBuf>,
+}
+
+impl DenyList {
+    fn new(config: &HttpdConfig) -> Self {
+        let patterns = |configured: &Option<Vec<String>>, defaults: &[&str]| -> Vec<String> {
+            match configured {
+                Some(patterns) => patterns.clone(),
+                None => defaults.iter().map(|p| p.to_string()).collect(),
+            }
+        };
+
+        Self {
+            deny: build_glob_set(&patterns(&config.deny, DEFAULT_DENY)),
+            allow: build_glob_set(&patterns(&config.allow, DEFAULT_ALLOW)),
+            key_file: fs::canonicalize(config.key_file()).ok(),
+        }
+    }
+
+    /// Returns why the path is denied, if it is
+    ///
+    /// `relative` is the path relative to the document root, which the patterns are matched
+    /// against, and `path` is the full path.
+    fn matches(&self, relative: &Path, path: &Path) -> Option<String> {
+        if let Some(key_file) = &self.key_file {
+            if path == key_file.as_path() {
+                return Some("it's the TLS private key".to_string());
+            }
+        }
+
+        // Check every directory on the way down as well as the path itself. Allowing a directory
+        // only exempts the directory, so what's in it still has to get past the deny rules, and
+        // nothing allowed inside a denied directory gets out of it
+        let mut prefix = PathBuf::new();
+        let mut denied = false;
+        for component in relative.components() {
+            prefix.push(component);
+            denied = denied || (self.deny.is_match(&prefix) && !self.allow.is_match(&prefix));
+        }
+
+        if denied {
+            Some(format!("{} matches a deny rule", relative.display()))
+        } else {
+            None
+        }
+    }
+}
+
+pub fn build_glob_set(patterns: &[String]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.trim_start_matches('/');
+        let pattern = if pattern.contains('/') {
+            pattern.to_string()
+        } else {
+            format!("**/{}", pattern)
+        };
+
+        match GlobBuilder::new(&pattern).literal_separator(true).build() {
+            Ok(glob) => { builder.add(glob); },
+            Err(e) => println!("Ignoring invalid pattern {:?}: {}", pattern, e),
         }
     }
 
+    builder.build().unwrap_or_else(|_| GlobSet::empty())
+}
+
+/// Whether the part of a URI after a mount's prefix is written the way its routes are, with no
+/// `.` or `..` segments and no doubled slashes
+fn is_canonical(relative: &str) -> bool {
+    let relative = relative.strip_prefix('/').unwrap_or(relative);
+    let relative = relative.strip_suffix('/').unwrap_or(relative);
+    relative.is_empty() || relative.split('/').all(|s| !s.is_empty() && s != "." && s != "..")
+}
+
+#[cfg(unix)]
+fn same_owner(link: &fs::Metadata, target: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    link.uid() == target.uid()
+}
+
+#[cfg(not(unix))]
+fn same_owner(_link: &fs::Metadata, _target: &fs::Metadata) -> bool {
+    true
+}
+
+fn _create_directory_routes(dir: &Path, prefix: &str, routes: &mut Routes, mount: &Mount) {
+    let root = &mount.root;
+
+    // Add the index for the directory, if it exists
+    if let Some(index) = mount.find_index(dir) {
+        routes.insert(prefix.to_string(), index);
+    }
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("Error reading {}: {}", dir.display(), e);
+            return;
+        }
+    };
+
     // Insert URI-to-path resolutions for this directory
-    for entry in fs::read_dir(dir).unwrap() {
-        let abs_path = if let Ok(de) = entry {
-            de.path()
+    for entry in entries {
+        let entry = if let Ok(de) = entry {
+            de
         } else {
             continue;
         };
 
-        let component = abs_path.components().last().unwrap();
+        let abs_path = entry.path();
+        let component = abs_path.components().next_back().unwrap();
 
         if let Component::Normal(osstr) = component {
             let path = Path::new(osstr);
 
+            // Links are left for route_to_new to resolve when they're asked for, which also keeps
+            // links to a parent directory from sending this round in circles
+            if entry.file_type().map(|t| t.is_symlink()).unwrap_or(true) {
+                continue;
+            }
+
+            if root.check_denied(&abs_path).is_err() {
+                continue;
+            }
+
             if abs_path.is_dir() {
-                let new_dir = dir.join(&path);
                 let new_prefix = format!("{}{}/",
                                          prefix,
                                          path.to_str().unwrap()
-                                             .trim_left_matches("./"));
-                _create_directory_routes(&dir.join(&path), &new_prefix, routes);
+                                             .trim_start_matches("./"));
+                _create_directory_routes(&dir.join(path), &new_prefix, routes, mount);
             } else if abs_path.is_file() {
-                let uri = format!("{}{}", prefix, path.to_str().unwrap().trim_left_matches("./"));
-                routes.insert(utf8_percent_encode(&uri, FRAGMENTS).to_string(), dir.join(&path));
+                let uri = format!("{}{}", prefix, path.to_str().unwrap().trim_start_matches("./"));
+                routes.insert(uri, dir.join(path));
             } else {
                 continue;
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn confines_paths_to_the_document_root() {
+        let dir = std::env::temp_dir().join(format!("selfserve-confine-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/file.txt"), "hi").unwrap();
+
+        let config = HttpdConfig::for_test("");
+        let root = DocumentRoot::new(&dir, &config);
+        let forbidden = |p: &str| matches!(root.confine(Path::new(p)), Err(RouteError::Forbidden(_)));
+
+        assert_eq!(root.confine(Path::new("sub/../sub/./file.txt")), Ok(root.path.join("sub/file.txt")));
+        assert_eq!(root.confine(Path::new("sub/missing.txt")), Err(RouteError::NotFound));
+        assert!(forbidden("../etc/passwd"));
+        assert!(forbidden("sub/../../etc/passwd"));
+        assert!(forbidden("/etc/passwd"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn denies_sensitive_files() {
+        let config = HttpdConfig::for_test(r#"deny: [".*", "*.bak", "/private/*"]"#);
+        let deny = DenyList::new(&config);
+        let denied = |p: &str| deny.matches(Path::new(p), Path::new(p)).is_some();
+
+        assert!(denied(".env"));
+        assert!(denied(".git/config"));
+        assert!(denied("sub/.git/HEAD"));
+        assert!(denied("docs/old.bak"));
+        assert!(denied("private/notes.txt"));
+        assert!(!denied("sub/private/notes.txt"));
+        assert!(!denied("index.html"));
+        assert!(!denied("a.bak.txt"));
+        assert!(!denied(".well-known/acme-challenge/token"));
+        assert!(denied(".well-known/.git/config"));
+        assert!(denied(".well-known/acme-challenge/.secret"));
+        assert!(denied(".well-known/old.bak"));
+        assert!(denied(".git/.well-known/secret"));
+
+        let config = HttpdConfig::for_test(r#"deny: ["**/.git"], allow: ["**/.well-known"]"#);
+        let deny = DenyList::new(&config);
+        let denied = |p: &str| deny.matches(Path::new(p), Path::new(p)).is_some();
+        assert!(denied(".git/.well-known/secret"));
+        assert!(denied("sub/.git/.well-known"));
+        assert!(!denied("sub/.well-known/secret"));
+    }
+
+    #[test]
+    fn picks_the_most_specific_mount() {
+        let config = HttpdConfig::for_test(r#"root: "src",
+            mounts: [(prefix: "/static/", path: "src/http"), (prefix: "/static/error", path: "src/error_pages")],"#);
+        let router = Router::from_config(&config);
+        let prefix = |uri| router.mount_for(uri).map(|m| m.prefix.as_str());
+
+        assert_eq!(prefix("/"), Some(""));
+        assert_eq!(prefix("/staticky.txt"), Some(""));
+        assert_eq!(prefix("/static"), Some("/static"));
+        assert_eq!(prefix("/static/mod.rs"), Some("/static"));
+        assert_eq!(prefix("/static/error/404.html"), Some("/static/error"));
+        assert_eq!(router.route_to("/static/error/404.html"), Some(router.mounts()[0].root.path.join("404.html")));
+    }
+
+    #[test]
+    fn updates_routes_as_files_change() {
+        let dir = std::env::temp_dir().join(format!("selfserve-update-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("old.txt"), "hi").unwrap();
+
+        let config = HttpdConfig::for_test(&format!("root: {:?}", dir));
+        let mut router = Router::from_config(&config);
+        let root = router.roots().remove(0);
+        assert_eq!(router.route_to("/old.txt"), Some(root.join("old.txt")));
+        assert_eq!(router.route_to_new("/sub/"), Ok(root.join("sub")));
+
+        // Other ways of writing a URI are resolved, but not remembered
+        for alias in ["/./old.txt", "/sub/../old.txt", "//old.txt", "/sub//"] {
+            assert!(router.route_to_new(alias).is_ok());
+            assert_eq!(router.route_to(alias), None);
+        }
+
+        fs::rename(dir.join("old.txt"), dir.join("new file.txt")).unwrap();
+        fs::write(dir.join("sub/index.html"), "hi").unwrap();
+        router.update(&root.join("old.txt"));
+        router.update(&root.join("new file.txt"));
+        router.update(&root.join("sub/index.html"));
+
+        assert_eq!(router.route_to("/old.txt"), None);
+        assert_eq!(router.route_to("/new file.txt"), Some(root.join("new file.txt")));
+        assert_eq!(router.route_to("/sub/"), Some(root.join("sub/index.html")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expands_try_files() {
+        let config = HttpdConfig::for_test(r#"root: "src", spa: true,
+            mounts: [(prefix: "/docs", path: "src/http", try_files: ["$uri", "$uri.html", "404.html", "=404"])],"#);
+        let router = Router::from_config(&config);
+        let try_files = |uri| router.mount_for(uri).unwrap().try_files(uri);
+        let uri = |uri: &str| TryFile::Uri(uri.to_string());
+
+        assert_eq!(try_files("/dashboard/settings"), vec![
+            uri("/dashboard/settings"), uri("/dashboard/settings/"), uri("/index.html"),
+        ]);
+        assert_eq!(try_files("/docs/guide"), vec![
+            uri("/docs/guide"), uri("/docs/guide.html"), uri("/docs/404.html"), TryFile::Status(StatusCode::NotFound),
+        ]);
+        assert_eq!(try_files("/docs")[0], uri("/docs"));
+    }
+}
@@ -1,5 +1,6 @@
 use std::thread;
 use std::sync::{
+    atomic::{AtomicUsize, Ordering},
     mpsc,
     Arc,
     Mutex
@@ -26,7 +27,7 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Self {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>, queued: Arc<AtomicUsize>) -> Self {
         let thread = thread::spawn(move || {
             loop {
                 let message = receiver.lock().unwrap()
@@ -35,6 +36,7 @@ impl Worker {
                 match message {
                     Message::NewJob(job) => {
                         #[cfg(test)] println!("Worker {} got a job", id);
+                        queued.fetch_sub(1, Ordering::SeqCst);
                         job.call_box();
                     },
 
@@ -58,7 +60,9 @@ type Job = Box<dyn FnBox + Send + 'static>;
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>
+    sender: mpsc::Sender<Message>,
+    /// How many jobs are waiting for a worker
+    queued: Arc<AtomicUsize>,
 }
 
 impl ThreadPool {
@@ -67,23 +71,32 @@ impl ThreadPool {
 
         let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
 
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&receiver), Arc::clone(&queued)));
         }
 
         Self {
             workers,
             sender,
+            queued,
         }
     }
 
+    /// The count of jobs waiting for a worker, for jobs that hold on to theirs for a while to
+    /// check whether they should give it up
+    pub fn queued(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.queued)
+    }
+
     pub fn execute<F>(&self, f: F)
         where F: FnOnce() + Send + 'static
     {
         let job = Box::new(f);
+        self.queued.fetch_add(1, Ordering::SeqCst);
         self.sender.send(Message::NewJob(job)).unwrap();
     }
 }
@@ -0,0 +1,499 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use percent_encoding::utf8_percent_encode;
+
+use crate::autoindex::SEGMENT;
+use crate::http::{HttpRequest, HttpResponse, Method, ParseError, ServerUpload, StatusCode, MAX_FORM_LEN};
+use crate::http::body::ResponseBody;
+use crate::http::multipart::{self, Multipart};
+use crate::http::utils::{escape_html, fill_placeholders};
+
+/// How big each uploaded file can be by default, in bytes
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How big a whole upload can be by default, in bytes
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 50 * 1024 * 1024;
+
+/// The page the summary is rendered into
+const TEMPLATE: &str = include_str!("upload.html");
+
+/// Names Windows won't open as files, whatever extension they're given
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Where a site takes uploads, and where it puts them
+pub struct Upload {
+    /// The path forms are posted to
+    pub uri: String,
+    /// The directory files are saved in, which is somewhere under the site's root
+    dir: PathBuf,
+    /// The URL the directory is served at, with a trailing slash
+    url: String,
+    max_file_size: u64,
+    max_size: u64,
+    overwrite: bool,
+}
+
+/// What came of an upload
+#[derive(Debug, Default)]
+pub struct Uploaded {
+    pub files: Vec<SavedFile>,
+    /// The form's other fields, in the order they were sent
+    pub fields: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+pub struct SavedFile {
+    /// The name of the form field the file was sent in
+    pub field: String,
+    /// The name the client gave the file
+    pub filename: String,
+    /// The name it was saved under
+    pub name: String,
+    pub url: String,
+    pub size: u64,
+    pub content_type: String,
+}
+
+/// Why an upload was refused, and the status to say so with
+#[derive(Debug)]
+pub struct UploadError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl UploadError {
+    fn new<M: Into<String>>(status: StatusCode, message: M) -> Self {
+        Self { status, message: message.into() }
+    }
+}
+
+impl From<ParseError> for UploadError {
+    fn from(e: ParseError) -> Self {
+        Self::new(e.status(), e.to_string())
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl Upload {
+    /// Sets up the endpoint for a site with its documents in `root`, making the directory if it
+    /// isn't there yet
+    ///
+    /// The directory has to be inside the root, so the files can be served back.
+    pub fn new(settings: &ServerUpload, root: &Path) -> io::Result<Self> {
+        let relative = Path::new(&settings.dir);
+        if relative.as_os_str().is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "the directory has to be a relative path inside the root"));
+        }
+
+        fs::create_dir_all(root.join(relative))?;
+
+        // A link could still lead out of the root
+        let dir = fs::canonicalize(root.join(relative))?;
+        if !dir.starts_with(fs::canonicalize(root)?) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "the directory leads outside the root"));
+        }
+
+        let url: Vec<String> = relative.components()
+            .map(|c| utf8_percent_encode(&c.as_os_str().to_string_lossy(), SEGMENT).to_string())
+            .collect();
+
+        Ok(Self {
+            uri: settings.uri.clone(),
+            dir,
+            url: format!("/{}/", url.join("/")),
+            max_file_size: settings.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+            max_size: settings.max_size.unwrap_or(DEFAULT_MAX_UPLOAD_SIZE),
+            overwrite: settings.overwrite.unwrap_or(false),
+        })
+    }
+
+    /// Whether a request is an upload to the endpoint
+    pub fn accepts(&self, request: &HttpRequest) -> bool {
+        request.method == Method::Post && request.path == self.uri
+    }
+
+    /// Saves the files in a `multipart/form-data` body, and collects the rest of the form's fields
+    ///
+    /// A file that can't be saved in full is removed, but the ones before it are kept. The body
+    /// is left partway through if anything goes wrong.
+    pub fn receive<R: Read>(&self, request: &HttpRequest, body: R) -> Result<Uploaded, UploadError> {
+        let boundary = request.headers.get("Content-Type")
+            .and_then(multipart::boundary)
+            .ok_or_else(|| UploadError::new(StatusCode::UnsupportedMediaType, "uploads have to be sent as multipart/form-data"))?;
+
+        // There's no point reading a body that's already said it's too big
+        let length = request.headers.get("Content-Length").and_then(|len| len.parse::<u64>().ok());
+        if length.is_some_and(|len| len > self.max_size) {
+            return Err(ParseError::BodyTooLarge(self.max_size).into());
+        }
+
+        let mut form = Multipart::new(Limited { inner: body, left: self.max_size, max: self.max_size }, &boundary);
+        let mut uploaded = Uploaded::default();
+
+        while let Some(mut part) = form.next_part()? {
+            let field = part.name().unwrap_or_default();
+            match part.filename() {
+                // Browsers send file inputs nobody picked a file for as an empty file with no name
+                Some(filename) if filename.is_empty() => {},
+                Some(filename) => {
+                    let content_type = part.content_type().unwrap_or("application/octet-stream").to_string();
+                    let (name, size) = self.save(&filename, &mut part)?;
+                    uploaded.files.push(SavedFile {
+                        url: format!("{}{}", self.url, utf8_percent_encode(&name, SEGMENT)),
+                        field,
+                        filename,
+                        name,
+                        size,
+                        content_type,
+                    });
+                },
+                None => {
+                    let mut value = vec![];
+                    (&mut part).take(MAX_FORM_LEN + 1).read_to_end(&mut value).map_err(ParseError::from)?;
+                    if value.len() as u64 > MAX_FORM_LEN {
+                        let message = format!("field {:?} is longer than {} bytes", field, MAX_FORM_LEN);
+                        return Err(UploadError::new(StatusCode::PayloadTooLarge, message));
+                    }
+                    uploaded.fields.push((field, String::from_utf8_lossy(&value).to_string()));
+                },
+            }
+        }
+
+        Ok(uploaded)
+    }
+
+    /// Saves a file under a safe version of its name, returning the name it got and its size
+    fn save<R: Read>(&self, filename: &str, content: &mut R) -> Result<(String, u64), UploadError> {
+        let failed = |e: io::Error| {
+            println!("Error saving upload {:?}: {}", filename, e);
+            UploadError::new(StatusCode::InternalServerError, format!("{:?} couldn't be saved", filename))
+        };
+        let (name, written, mut file) = self.create(&sanitize_filename(filename)).map_err(failed)?;
+
+        let path = self.dir.join(&name);
+        let copied = copy_limited(content, &mut file, self.max_file_size, filename);
+        drop(file);
+
+        // A file written under another name takes the place of whatever's there once it's whole,
+        // which replaces a link rather than writing to wherever it points
+        let saved = copied.and_then(|size| if written == path {
+            Ok(size)
+        } else {
+            fs::rename(&written, &path).map(|_| size).map_err(failed)
+        });
+        match saved {
+            Ok(size) => {
+                println!("Saved {} ({} bytes)", path.display(), size);
+                Ok((name, size))
+            },
+            Err(e) => {
+                let _ = fs::remove_file(&written);
+                Err(e)
+            },
+        }
+    }
+
+    /// Creates the file for an upload, numbering the name if it's taken and the endpoint doesn't
+    /// overwrite files, and returns the name along with where it's written
+    ///
+    /// Files that overwrite others are written to a hidden name next to them first, since opening
+    /// the name itself would follow a link that's there.
+    fn create(&self, name: &str) -> io::Result<(String, PathBuf, File)> {
+        if self.overwrite {
+            let (_, written, file) = self.create_numbered(&format!(".{}.part", name))?;
+            return Ok((name.to_string(), written, file));
+        }
+
+        self.create_numbered(name)
+    }
+
+    /// Creates a file under a name nothing has yet, numbering it like `name (1).ext` if it's taken
+    fn create_numbered(&self, name: &str) -> io::Result<(String, PathBuf, File)> {
+        let path = Path::new(name);
+        let stem = path.file_stem().map_or(name.into(), |s| s.to_string_lossy());
+        let extension = path.extension().map(|e| e.to_string_lossy());
+
+        let mut n = 0;
+        loop {
+            let candidate = match (n, &extension) {
+                (0, _) => name.to_string(),
+                (_, Some(extension)) => format!("{} ({}).{}", stem, n, extension),
+                (_, None) => format!("{} ({})", stem, n),
+            };
+
+            // Opening it with `create_new` claims the name even if another upload wants it too
+            match OpenOptions::new().write(true).create_new(true).open(self.dir.join(&candidate)) {
+                Ok(file) => return Ok((candidate.clone(), self.dir.join(&candidate), file)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Upload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.uri, self.dir.display())
+    }
+}
+
+/// Cuts a body off once it's longer than an upload can be, for bodies that are chunked and can't
+/// be checked before they're read
+struct Limited<R> {
+    inner: R,
+    left: u64,
+    max: u64,
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Reading one more byte than is left is how it's known to be over
+        let len = usize::try_from(self.left.saturating_add(1)).unwrap_or(usize::MAX).min(buf.len());
+        let n = self.inner.read(&mut buf[..len])?;
+        if n as u64 > self.left {
+            return Err(ParseError::BodyTooLarge(self.max).into());
+        }
+
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Copies a file out of an upload, failing if it's longer than `max`
+fn copy_limited<R: Read, W: Write>(from: &mut R, to: &mut W, max: u64, filename: &str) -> Result<u64, UploadError> {
+    let mut chunk = [0; 8192];
+    let mut copied = 0;
+
+    loop {
+        let n = from.read(&mut chunk).map_err(ParseError::from)?;
+        if n == 0 {
+            return Ok(copied);
+        }
+
+        copied += n as u64;
+        if copied > max {
+            let message = format!("{:?} is larger than {} bytes", filename, max);
+            return Err(UploadError::new(StatusCode::PayloadTooLarge, message));
+        }
+
+        to.write_all(&chunk[..n]).map_err(|e| {
+            println!("Error saving upload {:?}: {}", filename, e);
+            UploadError::new(StatusCode::InternalServerError, format!("{:?} couldn't be saved", filename))
+        })?;
+    }
+}
+
+/// Makes the name a client gave a file safe to save it under
+///
+/// Only the last part of a path is kept, and anything that's awkward in a file name on Windows or
+/// Unix is taken out. Leading dots go too, so uploads can't be hidden files.
+pub fn sanitize_filename(filename: &str) -> String {
+    let filename = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = filename.chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if "<>:\"|?*".contains(c) { '_' } else { c })
+        .collect();
+
+    let mut name = cleaned.trim_matches(|c| c == '.' || c == ' ').to_string();
+    while name.len() > 255 {
+        name.pop();
+    }
+
+    if name.is_empty() {
+        return "upload".to_string();
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem.trim_end())) {
+        name.insert(0, '_');
+    }
+
+    name
+}
+
+/*** SUMMARIES ***/
+
+/// Sums up an upload, as JSON if the client asked for it with `?format=json` or by preferring it
+/// in `Accept`, and as a page otherwise
+pub fn respond<'r>(request: &'r HttpRequest, result: &Result<Uploaded, UploadError>) -> HttpResponse<'r> {
+    let status = match result {
+        Ok(_) => StatusCode::Ok,
+        Err(e) => e.status,
+    };
+
+    let response = if wants_json(request) {
+        HttpResponse::empty(request, status)
+            .with_header("Content-Type", "application/json")
+            .with_body(ResponseBody::Bytes(to_json(result).into_bytes()))
+    } else {
+        HttpResponse::html(request, status, to_html(result))
+    };
+
+    response.with_header("Cache-Control", "no-store")
+}
+
+fn wants_json(request: &HttpRequest) -> bool {
+    if let Some(format) = request.query_params().get("format") {
+        return format == "json";
+    }
+
+    request.header("Accept").is_some_and(|accept| {
+        let first = accept.split(',').next().unwrap_or_default();
+        first.split(';').next().unwrap_or_default().trim() == "application/json"
+    })
+}
+
+fn to_json(result: &Result<Uploaded, UploadError>) -> String {
+    let uploaded = match result {
+        Ok(uploaded) => uploaded,
+        Err(e) => return format!("{{\"status\":{},\"error\":{}}}\n", e.status.code(), json_string(&e.message)),
+    };
+
+    let files: Vec<String> = uploaded.files.iter()
+        .map(|file| format!(
+            "{{\"field\":{},\"filename\":{},\"name\":{},\"url\":{},\"size\":{},\"type\":{}}}",
+            json_string(&file.field), json_string(&file.filename), json_string(&file.name),
+            json_string(&file.url), file.size, json_string(&file.content_type)
+        ))
+        .collect();
+    let fields: Vec<String> = uploaded.fields.iter()
+        .map(|(name, value)| format!("{{\"name\":{},\"value\":{}}}", json_string(name), json_string(value)))
+        .collect();
+
+    format!("{{\"files\":[{}],\"fields\":[{}]}}\n", files.join(","), fields.join(","))
+}
+
+fn to_html(result: &Result<Uploaded, UploadError>) -> String {
+    let uploaded = match result {
+        Ok(uploaded) => uploaded,
+        Err(e) => {
+            return render_page("Upload failed", &format!("    <p>{}</p>", escape_html(&e.to_string())));
+        }
+    };
+
+    let mut summary = format!(
+        "    <p>Saved {} file{}.</p>\n",
+        uploaded.files.len(), if uploaded.files.len() == 1 { "" } else { "s" }
+    );
+
+    if !uploaded.files.is_empty() {
+        summary.push_str("    <table>\n        <tr><th>Field</th><th>File</th><th>Saved as</th><th>Size</th><th>Type</th></tr>\n");
+        for file in &uploaded.files {
+            summary.push_str(&format!(
+                "        <tr><td>{}</td><td>{}</td><td><a href=\"{}\">{}</a></td><td class=\"size\">{}</td><td>{}</td></tr>\n",
+                escape_html(&file.field), escape_html(&file.filename), escape_html(&file.url),
+                escape_html(&file.name), file.size, escape_html(&file.content_type)
+            ));
+        }
+        summary.push_str("    </table>\n");
+    }
+
+    if !uploaded.fields.is_empty() {
+        summary.push_str("    <h2>Fields</h2>\n    <table>\n");
+        for (name, value) in &uploaded.fields {
+            summary.push_str(&format!("        <tr><th>{}</th><td>{}</td></tr>\n", escape_html(name), escape_html(value)));
+        }
+        summary.push_str("    </table>\n");
+    }
+
+    render_page("Upload complete", summary.trim_end())
+}
+
+/// Fills in the page in one pass, so names and values with placeholders in them are left as they are
+fn render_page(title: &str, summary: &str) -> String {
+    fill_placeholders(TEMPLATE, |name| match name {
+        "title" => Some(title.to_string()),
+        "summary" => Some(summary.to_string()),
+        _ => None,
+    })
+}
+
+/// Quotes and escapes a string for JSON
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sanitizes_filenames() {
+        assert_eq!(sanitize_filename("report.pdf"), "report.pdf");
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("C:\\Users\\jo\\photo.jpg"), "photo.jpg");
+        assert_eq!(sanitize_filename(".htaccess"), "htaccess");
+        assert_eq!(sanitize_filename("what?<now>.txt"), "what__now_.txt");
+        assert_eq!(sanitize_filename("tab\there\n.txt. "), "tabhere.txt");
+        assert_eq!(sanitize_filename("con.txt"), "_con.txt");
+        assert_eq!(sanitize_filename(".."), "upload");
+        assert_eq!(sanitize_filename(&"é".repeat(200)).len(), 254);
+        assert_eq!(json_string("say \"hi\"\n\u{1}"), "\"say \\\"hi\\\"\\n\\u0001\"");
+    }
+
+    #[test]
+    fn renders_summaries_in_one_pass() {
+        let page = to_html(&Ok(Uploaded { files: vec![], fields: vec![("{{summary}}".to_string(), "{{title}}".to_string())] }));
+        assert!(page.contains("<tr><th>{{summary}}</th><td>{{title}}</td></tr>"));
+        assert_eq!(page.matches("Upload complete").count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn overwrites_links_instead_of_following_them() {
+        let dir = std::env::temp_dir().join(format!("selfserve-upload-{}", std::process::id()));
+        fs::create_dir_all(dir.join("root")).unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+
+        let settings = ServerUpload {
+            uri: "/upload".to_string(),
+            dir: "files".to_string(),
+            max_file_size: None,
+            max_size: None,
+            overwrite: Some(true),
+        };
+        let upload = Upload::new(&settings, &dir.join("root")).unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), dir.join("root/files/a.txt")).unwrap();
+
+        let request: HttpRequest = "POST /upload HTTP/1.1\r\nHost: a\r\nContent-Type: multipart/form-data; boundary=x\r\n\r\n"
+            .parse()
+            .unwrap();
+        let body = "--x\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\r\nnew\r\n--x--\r\n";
+        let uploaded = upload.receive(&request, body.as_bytes()).unwrap();
+
+        assert_eq!(uploaded.files[0].name, "a.txt");
+        assert_eq!(fs::read_to_string(dir.join("secret")).unwrap(), "secret");
+        assert_eq!(fs::read_to_string(dir.join("root/files/a.txt")).unwrap(), "new");
+        assert!(!fs::symlink_metadata(dir.join("root/files/a.txt")).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_dir(dir.join("root/files")).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
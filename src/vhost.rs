@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::http::{HttpdConfig, HttpResponse, ServerUpload, VirtualHost};
+use crate::http::error_pages::ErrorPages;
+use crate::routing::Router;
+use crate::rewrite::Rules;
+use crate::upload::Upload;
+
+/// Everything served for one set of host names
+pub struct Site {
+    /// Exact names like `example.com` or wildcards like `*.example.com`
+    pub names: Vec<String>,
+    pub router: RwLock<Router>,
+    /// Redirects and rewrites, which run before anything's routed
+    pub rules: Rules,
+    /// Extra headers added to every response
+    pub headers: HashMap<String, String>,
+    /// The pages sent for error statuses
+    pub error_pages: ErrorPages,
+    /// Where forms can post files to be saved, if anywhere
+    pub upload: Option<Upload>,
+}
+
+impl Site {
+    fn from_virtual_host(vhost: &VirtualHost, config: &HttpdConfig) -> Self {
+        Self {
+            names: vhost.server_names.iter().map(|n| normalize_host(n)).collect(),
+            router: RwLock::new(Router::new(
+                vhost.root_mount(),
+                vhost.mounts.as_deref().unwrap_or(&[]),
+                config
+            )),
+            rules: Rules::new(vhost.rules.as_deref().unwrap_or(&[])),
+            headers: vhost.headers.clone().unwrap_or_default(),
+            // The host's own pages take over from the server's
+            error_pages: ErrorPages::new(
+                config.error_pages.iter().chain(&vhost.error_pages).flatten()
+                    .map(|(status, page)| (*status, page.clone()))
+                    .collect(),
+                config.owner.clone()
+            ),
+            upload: load_upload(vhost.upload.as_ref(), &vhost.root),
+        }
+    }
+
+    /// How well one of the site's names matches a host, if any do
+    ///
+    /// Exact names beat wildcards, and longer wildcards beat shorter ones.
+    fn match_host(&self, host: &str) -> Option<usize> {
+        self.names.iter()
+            .filter_map(|name| {
+                if name == host {
+                    Some(usize::MAX)
+                } else if name == "*" {
+                    Some(0)
+                } else if let Some(domain) = name.strip_prefix('*') {
+                    // `*.example.com` covers any subdomain but not `example.com` itself
+                    if domain.starts_with('.') && host.ends_with(domain) && host.len() > domain.len() {
+                        Some(domain.len())
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            })
+            .max()
+    }
+
+    /// Adds the site's headers to a response and gives it the site's page for an error status
+    pub fn customize<'r>(&'r self, response: HttpResponse<'r>) -> HttpResponse<'r> {
+        let response = self.error_pages.apply(response);
+
+        self.headers.iter()
+            .fold(response, |response, (name, value)| response.with_header(name, value))
+    }
+}
+
+/// Every site the server hosts
+pub struct Sites {
+    sites: Vec<Site>,
+    /// The site for hosts none of the others are for, if there is one
+    default: Option<usize>,
+    /// The pages sent for error statuses when there's no site to send its own
+    pub error_pages: ErrorPages,
+}
+
+impl Sites {
+    /// Creates a site for every virtual host in the config, or a single site for the top-level
+    /// root and mounts if there aren't any
+    pub fn from_config(config: &HttpdConfig) -> Self {
+        let error_pages = ErrorPages::new(config.error_pages.clone().unwrap_or_default(), config.owner.clone());
+        let vhosts = match &config.virtual_hosts {
+            Some(vhosts) if !vhosts.is_empty() => vhosts,
+            _ => {
+                return Self {
+                    sites: vec![Site {
+                        names: vec![],
+                        router: RwLock::new(Router::from_config(config)),
+                        rules: Rules::new(config.rules.as_deref().unwrap_or(&[])),
+                        headers: HashMap::new(),
+                        error_pages: error_pages.clone(),
+                        upload: load_upload(config.upload.as_ref(), config.root.as_deref().unwrap_or(".")),
+                    }],
+                    default: Some(0),
+                    error_pages,
+                };
+            }
+        };
+
+        Self {
+            sites: vhosts.iter().map(|vhost| Site::from_virtual_host(vhost, config)).collect(),
+            default: vhosts.iter().position(|vhost| vhost.default.unwrap_or(false)),
+            error_pages,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Site> {
+        self.sites.iter()
+    }
+
+    /// Picks the site for a host name, falling back to the default site
+    pub fn for_host(&self, host: Option<&str>) -> Option<&Site> {
+        let host = host.map(normalize_host);
+        host.and_then(|host| {
+            self.sites.iter()
+                .filter_map(|site| site.match_host(&host).map(|score| (score, site)))
+                .max_by_key(|(score, _)| *score)
+                .map(|(_, site)| site)
+        })
+            .or_else(|| self.default.map(|i| &self.sites[i]))
+    }
+}
+
+/// Sets up a site's upload endpoint, if it has one and its directory will do
+fn load_upload(settings: Option<&ServerUpload>, root: &str) -> Option<Upload> {
+    let settings = settings?;
+    match Upload::new(settings, Path::new(root)) {
+        Ok(upload) => Some(upload),
+        Err(e) => {
+            println!("Not taking uploads at {}: {}", settings.uri, e);
+            None
+        }
+    }
+}
+
+/// Lowercases a host name and strips the port and any trailing dot off it
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let name = if host.starts_with('[') {
+        // IPv6 literals have colons of their own
+        match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        }
+    } else {
+        match host.rfind(':') {
+            Some(colon) if host[colon + 1..].bytes().all(|b| b.is_ascii_digit()) => &host[..colon],
+            _ => host,
+        }
+    };
+
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches_host_names() {
+        let config = HttpdConfig::for_test(r#"
+            virtual_hosts: [
+                (server_names: ["example.com", "www.example.com"], root: "src"),
+                (server_names: ["*.example.com"], root: "src/http"),
+                (server_names: ["*.api.example.com"], root: "src/http", default: true),
+            ],"#);
+        let sites = Sites::from_config(&config);
+        let site = |host| sites.for_host(host).map(|s| s.names[0].as_str());
+
+        assert_eq!(site(Some("Example.COM:8080")), Some("example.com"));
+        assert_eq!(site(Some("www.example.com.")), Some("example.com"));
+        assert_eq!(site(Some("blog.example.com")), Some("*.example.com"));
+        assert_eq!(site(Some("v1.api.example.com")), Some("*.api.example.com"));
+        assert_eq!(site(Some("example.org")), Some("*.api.example.com"));
+        assert_eq!(site(None), Some("*.api.example.com"));
+        assert_eq!(normalize_host("[::1]:80"), "[::1]");
+    }
+}
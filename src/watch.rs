@@ -0,0 +1,78 @@
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::thread;
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::event::{AccessKind, AccessMode, ModifyKind};
+
+use crate::livereload::LiveReload;
+use crate::netlify;
+use crate::vhost::Sites;
+
+/// Watches every mounted directory, keeping the routers up to date as files come and go and
+/// telling pages to reload if `live_reload` is given
+///
+/// Uses inotify on Linux (and whatever the platform has elsewhere). Changes to what's in a file
+/// don't affect any routes, so only creations, removals and renames update them, besides changes
+/// to the `_headers` and `_redirects` files.
+pub fn watch_sites(sites: Arc<Sites>, live_reload: Option<Arc<LiveReload>>) -> notify::Result<()> {
+    let (tx, rx) = channel();
+    let mut watcher = RecommendedWatcher::new(tx, notify::Config::default())?;
+
+    for site in sites.iter() {
+        for root in site.router.read().unwrap().roots() {
+            if let Err(e) = watcher.watch(&root, RecursiveMode::Recursive) {
+                println!("Error watching {}: {}", root.display(), e);
+            }
+        }
+    }
+
+    thread::spawn(move || {
+        // The watcher stops as soon as it's dropped
+        let _watcher = watcher;
+
+        for event in rx {
+            let event: Event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    println!("Error watching for changes: {}", e);
+                    continue;
+                }
+            };
+
+            let changes_routes = matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_))
+            );
+            let changes_content = changes_routes || matches!(
+                event.kind,
+                EventKind::Modify(ModifyKind::Data(_)) | EventKind::Modify(ModifyKind::Any)
+            );
+
+            // The rules files are read again whenever they're saved. Closing them counts too, since
+            // the change event can come before everything's been written
+            let saves = changes_content
+                || matches!(event.kind, EventKind::Access(AccessKind::Close(AccessMode::Write)));
+            let changes_rules = saves && event.paths.iter().any(|path| {
+                path.file_name().is_some_and(|name| name == netlify::HEADERS_FILE || name == netlify::REDIRECTS_FILE)
+            });
+
+            if changes_routes || changes_rules {
+                for site in sites.iter() {
+                    let mut router = site.router.write().unwrap();
+                    for path in &event.paths {
+                        router.update(path);
+                    }
+                }
+            }
+
+            if let Some(live_reload) = live_reload.as_ref().filter(|_| changes_content) {
+                for path in &event.paths {
+                    live_reload.notify(path);
+                }
+            }
+        }
+    });
+
+    Ok(())
+}